@@ -0,0 +1,373 @@
+use crate::ax25;
+use crate::ax25::ax25_parser_client::Ax25ParserClient;
+use log::debug;
+
+// Bits of the SSID byte of an address.
+const ADDR_C_BIT: u8 = 0x80;
+const ADDR_RR_BITS: u8 = 0x60;
+const ADDR_RR1_BIT: u8 = 0x20;
+const ADDR_EXTENSION_BIT: u8 = 0x01;
+
+/// Bytes of one address field.
+const ADDR_LEN: usize = 7;
+
+/// Most repeaters in a digipeater path.
+const MAX_REPEATERS: usize = 8;
+
+// Control field of a UI frame, and its P/F bit.
+const CONTROL_UI: u8 = 0x03;
+const CONTROL_PF: u8 = 0x10;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Truncated,
+    BadAddress(String),
+    BadFcs { got: u16, want: u16 },
+    Unsupported(String),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "Truncated frame"),
+            Self::BadAddress(a) => write!(f, "Bad address {a:?}"),
+            Self::BadFcs { got, want } => write!(f, "Bad FCS {got:#06x}, want {want:#06x}"),
+            Self::Unsupported(what) => write!(f, "Can't encode {what}"),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// The FCS of a frame: CRC-16/X.25, sent low byte first.
+pub fn fcs(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for b in data {
+        crc ^= *b as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x8408
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Encode an address like `M0XXX-1`, with `flags` for the top three bits.
+fn encode_address(out: &mut Vec<u8>, addr: &str, flags: u8) -> Result<(), FrameError> {
+    let bad = || FrameError::BadAddress(addr.to_string());
+    let (call, ssid) = match addr.split_once('-') {
+        Some((call, ssid)) => (call, ssid.parse::<u8>().map_err(|_| bad())?),
+        None => (addr, 0),
+    };
+    if call.is_empty()
+        || call.len() > 6
+        || !call.bytes().all(|c| c.is_ascii_alphanumeric())
+        || ssid > 15
+    {
+        return Err(bad());
+    }
+    let call = format!("{:<6}", call.to_ascii_uppercase());
+    out.extend(call.bytes().map(|c| c << 1));
+    out.push(flags | (ssid << 1));
+    Ok(())
+}
+
+/// Decode one address field, to the address and the SSID byte.
+fn decode_address(field: &[u8]) -> (String, u8) {
+    let call: String = field[..6]
+        .iter()
+        .map(|c| (c >> 1) as char)
+        .collect::<String>()
+        .trim_end()
+        .to_string();
+    let ssid = (field[6] >> 1) & 0x0f;
+    let addr = match ssid {
+        0 => call,
+        ssid => format!("{call}-{ssid}"),
+    };
+    (addr, field[6])
+}
+
+/// The reserved bits of an address, set as the spec says unless `rr1`
+/// asks for the first one cleared.
+fn rr_bits(rr1: bool) -> u8 {
+    if rr1 {
+        ADDR_RR_BITS & !ADDR_RR1_BIT
+    } else {
+        ADDR_RR_BITS
+    }
+}
+
+///
+/// Encode a UI frame, with FCS if `set_fcs`.
+///
+/// The frame is the destination, source and repeater addresses, the
+/// control field, the PID and the payload. Only UI frames are supported,
+/// since that's all we send.
+///
+pub fn encode(packet: &ax25::Packet, set_fcs: bool) -> Result<Vec<u8>, FrameError> {
+    let ui = match &packet.frame_type {
+        Some(ax25::packet::FrameType::Ui(ui)) => ui,
+        other => return Err(FrameError::Unsupported(format!("{other:?}"))),
+    };
+    if packet.repeater.len() > MAX_REPEATERS {
+        return Err(FrameError::Unsupported(format!(
+            "{} repeaters",
+            packet.repeater.len()
+        )));
+    }
+    let mut out = Vec::with_capacity(ADDR_LEN * 2 + 4 + ui.payload.len());
+    let c_bit = |set| if set { ADDR_C_BIT } else { 0 };
+    encode_address(
+        &mut out,
+        &packet.dst,
+        c_bit(packet.command_response) | rr_bits(packet.rr_dst1),
+    )?;
+    encode_address(
+        &mut out,
+        &packet.src,
+        c_bit(packet.command_response_la) | rr_bits(packet.rr_extseq),
+    )?;
+    for r in &packet.repeater {
+        encode_address(
+            &mut out,
+            &r.address,
+            c_bit(r.has_been_repeated) | ADDR_RR_BITS,
+        )?;
+    }
+    *out.last_mut().expect("there are addresses") |= ADDR_EXTENSION_BIT;
+    out.push(if ui.push != 0 {
+        CONTROL_UI | CONTROL_PF
+    } else {
+        CONTROL_UI
+    });
+    out.push(ui.pid as u8);
+    out.extend(&ui.payload);
+    if set_fcs {
+        out.extend(fcs(&out).to_le_bytes());
+    }
+    Ok(out)
+}
+
+///
+/// Decode a frame, checking the FCS at the end if `check_fcs`.
+///
+/// Frames other than UI are decoded as far as the addresses, and come
+/// back without a frame type.
+///
+pub fn decode(frame: &[u8], check_fcs: bool) -> Result<ax25::Packet, FrameError> {
+    let (frame, got_fcs) = if check_fcs {
+        let (frame, got) = frame.split_last_chunk::<2>().ok_or(FrameError::Truncated)?;
+        let got = u16::from_le_bytes(*got);
+        let want = fcs(frame);
+        if got != want {
+            return Err(FrameError::BadFcs { got, want });
+        }
+        (frame, got)
+    } else {
+        (frame, 0)
+    };
+
+    // Addresses go on until one has the extension bit set.
+    let mut addrs = Vec::new();
+    let mut pos = 0;
+    loop {
+        let field = frame
+            .get(pos..pos + ADDR_LEN)
+            .ok_or(FrameError::Truncated)?;
+        addrs.push(decode_address(field));
+        pos += ADDR_LEN;
+        if field[6] & ADDR_EXTENSION_BIT != 0 {
+            break;
+        }
+        if addrs.len() == 2 + MAX_REPEATERS {
+            return Err(FrameError::BadAddress("too many repeaters".to_string()));
+        }
+    }
+    if addrs.len() < 2 {
+        return Err(FrameError::Truncated);
+    }
+    let (dst, dst_ssid) = addrs.remove(0);
+    let (src, src_ssid) = addrs.remove(0);
+    let control = *frame.get(pos).ok_or(FrameError::Truncated)?;
+    let frame_type = if control & !CONTROL_PF == CONTROL_UI {
+        let pid = *frame.get(pos + 1).ok_or(FrameError::Truncated)?;
+        Some(ax25::packet::FrameType::Ui(ax25::packet::Ui {
+            pid: pid as i32,
+            push: (control & CONTROL_PF != 0) as i32,
+            payload: frame[pos + 2..].to_vec(),
+        }))
+    } else {
+        None
+    };
+    Ok(ax25::Packet {
+        dst,
+        src,
+        repeater: addrs
+            .into_iter()
+            .map(|(address, ssid)| ax25::Repeater {
+                address,
+                has_been_repeated: ssid & ADDR_C_BIT != 0,
+            })
+            .collect(),
+        fcs: got_fcs as i32,
+        command_response: dst_ssid & ADDR_C_BIT != 0,
+        command_response_la: src_ssid & ADDR_C_BIT != 0,
+        rr_dst1: dst_ssid & ADDR_RR1_BIT == 0,
+        rr_extseq: src_ssid & ADDR_RR1_BIT == 0,
+        aprs: None,
+        frame_type,
+    })
+}
+
+///
+/// Turns AX.25 frames into packets and back, either with an ax25ms
+/// parser service, or natively without one.
+///
+#[derive(Clone)]
+pub struct Ax25Codec {
+    remote: Option<Ax25ParserClient<tonic::transport::Channel>>,
+}
+
+impl Ax25Codec {
+    /// Use the parser service at `addr`, or the native codec if none.
+    pub async fn connect(addr: Option<String>) -> Result<Ax25Codec, tonic::transport::Error> {
+        Ok(Ax25Codec {
+            remote: match addr {
+                Some(addr) => Some(Ax25ParserClient::connect(addr).await?),
+                None => None,
+            },
+        })
+    }
+
+    /// Decode a frame with its FCS, or `None` if it doesn't decode, like
+    /// if the FCS is bad.
+    pub async fn parse(
+        &mut self,
+        frame: Vec<u8>,
+    ) -> Result<Option<ax25::Packet>, Box<dyn std::error::Error>> {
+        let Some(remote) = &mut self.remote else {
+            return Ok(decode(&frame, true)
+                .inspect_err(|e| debug!("Ignoring frame: {}", e))
+                .ok());
+        };
+        Ok(remote
+            .parse(tonic::Request::new(ax25::ParseRequest {
+                payload: frame,
+                check_fcs: true,
+            }))
+            .await?
+            .into_inner()
+            .packet)
+    }
+
+    /// Encode a packet, with FCS.
+    pub async fn serialize(
+        &mut self,
+        packet: ax25::Packet,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let Some(remote) = &mut self.remote else {
+            return Ok(encode(&packet, true)?);
+        };
+        let req = tonic::Request::new(ax25::SerializeRequest {
+            packet: Some(packet),
+            set_fcs: true,
+        });
+        Ok(remote.serialize(req).await?.into_inner().payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ui_packet() -> ax25::Packet {
+        ax25::Packet {
+            dst: "CQ".to_string(),
+            src: "M0XXX-1".to_string(),
+            repeater: vec![
+                ax25::Repeater {
+                    address: "WIDE1-1".to_string(),
+                    has_been_repeated: true,
+                },
+                ax25::Repeater {
+                    address: "WIDE2-2".to_string(),
+                    has_been_repeated: false,
+                },
+            ],
+            command_response_la: true,
+            frame_type: Some(ax25::packet::FrameType::Ui(ax25::packet::Ui {
+                pid: 0xf0,
+                push: 0,
+                payload: b"hello".to_vec(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn fcs_check_value() {
+        assert_eq!(fcs(b"123456789"), 0x906e);
+    }
+
+    #[test]
+    fn address() {
+        let mut out = Vec::new();
+        encode_address(&mut out, "m0xxx-11", ADDR_C_BIT | ADDR_RR_BITS).unwrap();
+        assert_eq!(out, [0x9a, 0x60, 0xb0, 0xb0, 0xb0, 0x40, 0xf6]);
+        assert_eq!(decode_address(&out), ("M0XXX-11".to_string(), 0xf6));
+
+        for bad in ["", "TOOLONG", "M0XXX-16", "M0XXX-", "M0/XX"] {
+            assert!(encode_address(&mut out, bad, 0).is_err(), "{bad:?}");
+        }
+    }
+
+    #[test]
+    fn ui_round_trip() {
+        let packet = ui_packet();
+        let frame = encode(&packet, true).unwrap();
+        let decoded = decode(&frame, true).unwrap();
+        assert_eq!(decoded.fcs as u16, fcs(&frame[..frame.len() - 2]));
+        assert_eq!(
+            decoded,
+            ax25::Packet {
+                fcs: decoded.fcs,
+                ..packet
+            }
+        );
+    }
+
+    #[test]
+    fn too_many_repeaters() {
+        let mut packet = ui_packet();
+        packet.repeater = vec![packet.repeater[0].clone(); MAX_REPEATERS + 1];
+        assert!(encode(&packet, true).is_err());
+    }
+
+    #[test]
+    fn bad_fcs() {
+        let mut frame = encode(&ui_packet(), true).unwrap();
+        let last = frame.len() - 3;
+        frame[last] ^= 1;
+        assert!(matches!(
+            decode(&frame, true),
+            Err(FrameError::BadFcs { .. })
+        ));
+        // Without the check it's just a different payload.
+        assert!(decode(&frame[..frame.len() - 2], false).is_ok());
+    }
+
+    #[test]
+    fn truncated() {
+        let frame = encode(&ui_packet(), false).unwrap();
+        for len in [0, 6, 13, 20] {
+            assert!(matches!(
+                decode(&frame[..len], false),
+                Err(FrameError::Truncated)
+            ));
+        }
+    }
+}
@@ -1,38 +1,70 @@
-use ax25::ax25_parser_client::Ax25ParserClient;
 use ax25::packet::FrameType::Ui;
-use ax25ms::router_service_client::RouterServiceClient;
-use ax25ms::StreamRequest;
 use clap::Parser;
+use ed25519_dalek::VerifyingKey;
 use futures::{pin_mut, select};
 use futures_timer::Delay;
 use futures_util::FutureExt;
-use futures_util::StreamExt;
-use lazy_static::lazy_static;
+use lib::ax25codec::Ax25Codec;
+use lib::compression::{Compression, Decompressor};
+use lib::delta;
+use lib::kiss::KissOpt;
+use lib::layout::BlockLayout;
+use lib::manifest::{self, Manifest};
+use lib::merkle;
+use lib::protocol::{
+    Capabilities, Codec, ErrorReason, FileInfo, FindEntry, Hash, HashPrefix, ListEntry, Message,
+    ProtocolError, FEATURE_DELTA, FEATURE_SACK, FEATURE_TREE, FEC_RAPTOR, MIN_PACKET_SIZE,
+};
+use lib::sack::EsiSet;
+use lib::signing::{load_trusted_keys, verify};
+use lib::spool::Spool;
+use lib::transport::Transport;
 use lib::{ax25, ax25ms, make_packet};
 use log::{debug, info, warn};
 use rand::Rng;
-use regex::Regex;
+use std::collections::{HashMap, HashSet};
 use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 use tokio::sync::mpsc;
 use tokio::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Opt {
-    #[clap(short, long = "router")]
-    router: String,
+    /// ax25ms router to receive frames from, and send them with unless
+    /// --tx_router is given.
+    #[clap(
+        short,
+        long = "router",
+        required_unless_present_any = ["kiss_tcp", "kiss_serial"],
+        conflicts_with_all = ["kiss_tcp", "kiss_serial"]
+    )]
+    router: Option<String>,
 
-    #[clap(short = 'R', long = "tx_router", default_value = "")]
-    txrouter: String,
+    #[clap(
+        short = 'R',
+        long = "tx_router",
+        conflicts_with_all = ["kiss_tcp", "kiss_serial"]
+    )]
+    txrouter: Option<String>,
 
+    #[command(flatten)]
+    kiss: KissOpt,
+
+    /// ax25ms parser service for AX.25 frames. Without it, they're
+    /// encoded and decoded natively.
     #[clap(short, long = "parser")]
-    parser: String,
+    parser: Option<String>,
 
     #[clap(short, long = "source")]
     source: String,
 
+    /// Where to write the file. Defaults to the name the uploader has
+    /// for it.
     #[clap(short, long = "output")]
-    output: String,
+    output: Option<String>,
 
     #[clap(short, long = "dst", default_value = "CQ")]
     dst: String,
@@ -46,31 +78,217 @@ struct Opt {
     #[clap(short, long = "list")]
     list: bool,
 
-    // Positional argument.
-    roothash: String,
+    /// Send the text form of the protocol, for debugging.
+    #[clap(long = "text")]
+    text: bool,
+
+    /// File with public keys of uploaders to trust. If given, all
+    /// replies must be signed by one of them.
+    #[clap(long = "trusted-keys")]
+    trusted_keys: Option<String>,
+
+    /// Get capabilities and metadata before requesting data, instead of
+    /// all at once.
+    #[clap(long = "two-step")]
+    two_step: bool,
+
+    /// Largest encoding symbol we can receive.
+    #[clap(
+        long = "max-packet-size",
+        default_value = "65535",
+        value_parser = clap::value_parser!(u16).range(MIN_PACKET_SIZE as i64..)
+    )]
+    max_packet_size: u16,
+
+    /// Only send this many bytes of the hash in GET and META requests,
+    /// to save airtime. The data is still checked against all of it.
+    #[clap(long = "hash-prefix", default_value = "32", value_parser = clap::value_parser!(u8).range(4..=32))]
+    hash_prefix: u8,
+
+    /// Listen for other stations' transfers of the file for this many
+    /// seconds before requesting it, and then only request what's still
+    /// missing. Listening goes on for as long as the file is heard
+    /// about.
+    #[clap(long = "listen", default_value = "0")]
+    listen: f32,
+
+    /// Only get this range of the file, and write it at the same offset
+    /// of the output file.
+    #[clap(long = "range", value_name = "OFFSET:LENGTH", value_parser = parse_range, requires = "output")]
+    range: Option<(u64, u64)>,
+
+    /// Older copy of the file, so that only the differences need to be
+    /// sent.
+    #[clap(long = "basis", conflicts_with = "range")]
+    basis: Option<String>,
+
+    /// The hash is of a directory. Get it and everything in it, into
+    /// `--output` or the directory's name on the uploader. Files already
+    /// there are kept.
+    #[clap(long = "bundle", conflicts_with_all = ["range", "basis"])]
+    bundle: bool,
+
+    /// Don't request anything, just write every announced file that can
+    /// be decoded to this directory.
+    #[clap(long = "passive")]
+    passive: Option<String>,
+
+    /// Get every file and directory in the uploader's listing that this
+    /// directory doesn't already have.
+    #[clap(long = "sync", conflicts_with_all = ["list", "passive", "bundle", "range", "basis", "output"])]
+    sync: Option<String>,
+
+    /// With `--sync` or `--bundle`, remove local files that the uploader
+    /// doesn't have.
+    #[clap(long = "delete")]
+    delete: bool,
+
+    /// With `--sync`, sync again this many seconds after each sync,
+    /// forever.
+    #[clap(long = "interval", default_value = "0", requires = "sync")]
+    interval: u64,
+
+    /// Hash of the file, a unique prefix of it, or `name:PATTERN` to look
+    /// it up by name or glob pattern. With `--list`, list what matches the
+    /// pattern.
+    #[clap(required_unless_present_any = ["list", "passive", "sync"])]
+    roothash: Option<String>,
+}
+
+impl Opt {
+    fn codec(&self) -> Codec {
+        if self.text {
+            Codec::Text
+        } else {
+            Codec::Binary
+        }
+    }
+
+    /// As much of `hash` as to send in requests.
+    fn prefix(&self, hash: &Hash) -> HashPrefix {
+        HashPrefix::new(hash, self.hash_prefix as usize)
+    }
+}
+
+/// Parse `--range`.
+fn parse_range(s: &str) -> Result<(u64, u64), String> {
+    let (offset, length) = s
+        .split_once(':')
+        .ok_or("expected OFFSET:LENGTH".to_string())?;
+    Ok((
+        offset.parse().map_err(|e| format!("bad offset: {e}"))?,
+        length.parse().map_err(|e| format!("bad length: {e}"))?,
+    ))
+}
+
+/// Times in a row to ask for missing list pages without getting any,
+/// before giving up.
+const MAX_LIST_RETRIES: usize = 5;
+
+/// Times to ask for metadata before giving up.
+const MAX_META_RETRIES: usize = 5;
+
+/// Max list pages to ask for in one request, to stay within a packet.
+const MAX_LIST_PAGES_REQUEST: usize = 32;
+
+async fn send_message(
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    dst: &str,
+    src: &str,
+    msg: &Message,
+    codec: Codec,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let cmd = make_packet(parser, dst, src, msg.encode(codec)).await?;
+    txclient.send(cmd).await?;
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn request_block(
-    txclient: &mut RouterServiceClient<tonic::transport::Channel>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
     dst: &str,
     src: &str,
-    hash: &str,
+    hash: HashPrefix,
     tag: u16,
     existing: usize,
+    range: Option<(u64, u64)>,
+    delta: Option<(u64, u64)>,
+    codec: Codec,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let cmd = make_packet(
-        parser,
-        dst,
-        src,
-        format!("G {} 0 {} {}", tag, existing, hash).to_string(),
-    )
-    .await?;
-    txclient
-        .send(tonic::Request::new(ax25ms::SendRequest {
-            frame: Some(ax25ms::Frame { payload: cmd }),
-        }))
-        .await?;
+    let msg = Message::Get {
+        tag,
+        frequency: 0,
+        existing: existing as u64,
+        hash,
+        meta: false,
+        range,
+        delta,
+    };
+    send_message(txclient, parser, dst, src, &msg, codec).await
+}
+
+/// Ask for the chunk hashes still missing, if any.
+async fn request_tree(
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    hash: &Hash,
+    tag: u16,
+    verifier: &Option<Verifier>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let first = match verifier.as_ref().and_then(Verifier::missing) {
+        Some(first) => first,
+        None => return Ok(()),
+    };
+    debug!("Requesting chunk hashes from {}", first);
+    let msg = Message::Tree {
+        tag,
+        hash: *hash,
+        first,
+    };
+    send_message(txclient, parser, &opt.dst, &opt.source, &msg, opt.codec()).await
+}
+
+/// Tell the uploader which ESIs we have for the blocks not yet decoded.
+///
+/// Split over as many frames as needed to keep them within the packet size.
+#[allow(clippy::too_many_arguments)]
+async fn request_sack(
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    dst: &str,
+    src: &str,
+    hash: &Hash,
+    tag: u16,
+    layout: &BlockLayout,
+    decoders: &[BlockDecoder],
+    codec: Codec,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let sack = |blocks| Message::Sack {
+        tag,
+        hash: *hash,
+        blocks,
+    };
+    let mut msgs = Vec::new();
+    let mut blocks = Vec::new();
+    for (n, d) in decoders.iter().enumerate() {
+        if d.done() {
+            continue;
+        }
+        blocks.push((n as u16, d.esis.clone()));
+        if blocks.len() > 1 && sack(blocks.clone()).encode(codec).len() > layout.packet_size {
+            let last = blocks.pop().unwrap();
+            msgs.push(sack(std::mem::replace(&mut blocks, vec![last])));
+        }
+    }
+    if !blocks.is_empty() {
+        msgs.push(sack(blocks));
+    }
+    for msg in msgs {
+        send_message(txclient, parser, dst, src, &msg, codec).await?;
+    }
     Ok(())
 }
 
@@ -84,7 +302,7 @@ async fn receive_frame(
     pin_mut!(sfut, tfut);
     select! {
         f = sfut => {
-            let frame = f.unwrap().payload;
+            let frame = f.ok_or(DownloaderError::StreamEnded)?.payload;
             Ok(frame)
         },
         _ = tfut => {
@@ -94,120 +312,541 @@ async fn receive_frame(
     }
 }
 
+/// Decoding state of one source block.
+struct BlockDecoder {
+    decoder: raptor_code::SourceBlockDecoder,
+    source_symbols: usize,
+
+    /// Length of every encoding symbol of the block, from the layout.
+    encoding_symbol_length: usize,
+
+    /// Bytes of encoding symbols received.
+    received: usize,
+
+    /// ESIs received.
+    esis: EsiSet,
+}
+
+impl BlockDecoder {
+    /// Decoder of source block `n` of `layout`.
+    fn new(layout: &BlockLayout, n: usize) -> Self {
+        let source_symbols = layout.source_symbols(n);
+        Self {
+            decoder: raptor_code::SourceBlockDecoder::new(source_symbols),
+            source_symbols,
+            encoding_symbol_length: layout.block_range(n).len().div_ceil(source_symbols),
+            received: 0,
+            esis: EsiSet::new(),
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.decoder.fully_specified()
+    }
+
+    /// Add an encoding symbol. Returns false if it wasn't needed, or isn't
+    /// the length the layout says it should be.
+    fn add(&mut self, esi: u16, symbol: &[u8]) -> bool {
+        if self.done() || self.esis.contains(esi) {
+            return false;
+        }
+        if symbol.len() != self.encoding_symbol_length {
+            debug!(
+                "Dropping symbol of {} bytes, expected {}",
+                symbol.len(),
+                self.encoding_symbol_length
+            );
+            return false;
+        }
+        self.received += symbol.len();
+        self.esis.insert(esi);
+        self.decoder.push_encoding_symbol(symbol, esi as u32);
+        true
+    }
+
+    /// The source block, `len` bytes. Only once done.
+    fn decode(&mut self, len: usize) -> Option<Vec<u8>> {
+        let mut source_block = self
+            .decoder
+            .decode(self.encoding_symbol_length * self.source_symbols)?;
+        source_block.resize(len, 0); // Will only ever shrink.
+        Some(source_block)
+    }
+}
+
+/*
+* Checks a file chunk by chunk as its source blocks are decoded, against
+* the chunk hashes from the uploader, so that bad data is caught and
+* requested again as soon as it's decoded, instead of failing the whole
+* download at the end. This includes symbols from a spool file.
+*
+* Blocks are checked in order, since with compression there's no getting
+* at a chunk without decompressing everything before it.
+*/
+struct Verifier {
+    hash: Hash,
+
+    /// Chunk hashes, as they arrive. Only used once they're all here and
+    /// add up to the hash.
+    leaves: Vec<Option<Hash>>,
+    verified: bool,
+
+    layout: BlockLayout,
+    compression: Compression,
+    original_size: usize,
+
+    /// Decompression of blocks `..next_block`.
+    decompressor: Decompressor,
+    next_block: usize,
+
+    /// Decompressed data of chunk `chunk` on, not checked yet.
+    out: Vec<u8>,
+    chunk: usize,
+
+    /// First block with data of chunk `chunk`.
+    chunk_block: usize,
+}
+
+impl Verifier {
+    fn new(hash: Hash, meta: &Meta) -> Verifier {
+        Verifier {
+            hash,
+            leaves: vec![None; merkle::chunks(meta.original_size)],
+            verified: false,
+            layout: meta.layout,
+            compression: meta.compression,
+            original_size: meta.original_size,
+            decompressor: Decompressor::new(meta.compression, meta.original_size),
+            next_block: 0,
+            out: Vec::new(),
+            chunk: 0,
+            chunk_block: 0,
+        }
+    }
+
+    /// First chunk hash still missing.
+    fn missing(&self) -> Option<u64> {
+        self.leaves
+            .iter()
+            .position(Option::is_none)
+            .map(|n| n as u64)
+    }
+
+    /// Add chunk hashes from a tree reply. Once all are here, they're
+    /// checked against the hash, and thrown away if they don't match.
+    fn add_leaves(&mut self, first: u64, leaves: &[Hash]) {
+        for (n, leaf) in leaves.iter().enumerate() {
+            let Some(slot) = usize::try_from(first)
+                .ok()
+                .and_then(|f| f.checked_add(n))
+                .and_then(|i| self.leaves.get_mut(i))
+            else {
+                continue;
+            };
+            *slot = Some(*leaf);
+        }
+        if self.verified || self.missing().is_some() {
+            return;
+        }
+        let leaves: Vec<Hash> = self.leaves.iter().map(|l| l.unwrap()).collect();
+        if merkle::root(&leaves) == self.hash {
+            info!(
+                "Got {} chunk hashes, checking chunks as they arrive",
+                leaves.len()
+            );
+            self.verified = true;
+        } else {
+            warn!("Chunk hashes don't match the file hash, asking again");
+            self.leaves.fill(None);
+        }
+    }
+
+    /// Start over from the first block.
+    fn restart(&mut self) {
+        self.decompressor = Decompressor::new(self.compression, self.original_size);
+        self.next_block = 0;
+        self.out.clear();
+        self.chunk = 0;
+        self.chunk_block = 0;
+    }
+
+    /*
+     * Check the chunks of blocks decoded since last time, as far as the
+     * blocks before them are decoded. Blocks with a bad chunk are reset,
+     * to be received again. Returns the number of bytes of encoding
+     * symbols thrown away.
+     */
+    fn check(&mut self, decoders: &mut [BlockDecoder]) -> usize {
+        if !self.verified {
+            return 0;
+        }
+        while self.next_block < decoders.len() && decoders[self.next_block].done() {
+            let n = self.next_block;
+            let Some(block) = decoders[n].decode(self.layout.block_range(n).len()) else {
+                return self.reset(decoders, n);
+            };
+            self.next_block += 1;
+            let mut good = self.decompressor.feed(&block, &mut self.out).is_ok();
+            // Whole chunks, and after the last block whatever is left.
+            let last = self.next_block == decoders.len();
+            while good
+                && self.chunk < self.leaves.len()
+                && (last || self.out.len() >= merkle::CHUNK_SIZE)
+            {
+                let len = std::cmp::min(merkle::CHUNK_SIZE, self.out.len());
+                let chunk: Vec<u8> = self.out.drain(..len).collect();
+                good = self.leaves[self.chunk] == Some(merkle::leaf(&chunk));
+                if good {
+                    self.chunk += 1;
+                    self.chunk_block = n;
+                }
+            }
+            // Nothing should be left over at the end.
+            if last && !self.out.is_empty() {
+                good = false;
+            }
+            if !good {
+                return self.reset(decoders, n);
+            }
+        }
+        0
+    }
+
+    /// Reset the blocks that chunk `chunk` came from, up to `last`.
+    fn reset(&mut self, decoders: &mut [BlockDecoder], last: usize) -> usize {
+        let first = match self.compression {
+            Compression::None => self.chunk * merkle::CHUNK_SIZE / self.layout.block_size(),
+            // Only roughly known, from what was being decompressed.
+            _ => self.chunk_block,
+        };
+        warn!(
+            "Chunk {} is bad, receiving blocks {}..={} again",
+            self.chunk, first, last
+        );
+        let mut discarded = 0;
+        for (n, d) in decoders.iter_mut().enumerate().take(last + 1).skip(first) {
+            discarded += d.received;
+            *d = BlockDecoder::new(&self.layout, n);
+        }
+        self.restart();
+        discarded
+    }
+}
+
+/// Data received before the download proper started.
+struct EarlyData {
+    /// Tag the data was requested with. None if it was overheard from
+    /// someone else's transfer, and we haven't requested anything yet.
+    tag: Option<u16>,
+
+    /// Tag the data is sent with. Different if the uploader put us in a
+    /// session with other stations.
+    data_tag: u16,
+
+    /// Block, ESI, and encoding symbol.
+    symbols: Vec<(u16, u16, Vec<u8>)>,
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn receive_streamed_block(
-    decoder: &mut raptor_code::SourceBlockDecoder,
+    decoders: &mut [BlockDecoder],
     stream: &mut mpsc::Receiver<ax25ms::Frame>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
+    parser: &mut Ax25Codec,
     tag: u16,
+    data_tag: &mut u16,
+    tree_tag: u16,
+    verifier: &mut Option<Verifier>,
+    spool: &mut Option<Spool>,
     size: usize,                // Only needed for progress bar.
     bytes_received: &mut usize, // Only needed for progress bar.
     packet_loss: f32,
     timeout: f32,
-) -> Result<usize, DownloaderError> {
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(), DownloaderError> {
     info!("Awaiting data…");
-    let mut encoding_symbol_length = 0;
-    let mut rng = rand::thread_rng();
-    while !decoder.fully_specified() {
+    let mut rng = rand::rng();
+    // Chunk hashes still coming are worth waiting for, to check the data
+    // before it's all thrown away for one bad chunk.
+    while !decoders.iter().all(BlockDecoder::done) || verifier.as_ref().is_some_and(|v| !v.verified)
+    {
         // Get frame.
         let frame = receive_frame(stream, timeout).await?;
 
-        if rng.gen::<f32>() < packet_loss {
+        if rng.random::<f32>() < packet_loss {
             continue;
         }
 
         //
         // Parse UI frame.
         //
-        let parsed = parser
-            .parse(tonic::Request::new(ax25::ParseRequest {
-                payload: frame,
-                check_fcs: true,
-            }))
-            .await?
-            .into_inner()
-            .packet
-            .expect("surely the RPC reply has a packet");
+        let Some(parsed) = parser.parse(frame).await? else {
+            continue;
+        };
 
         let ui = match parsed.frame_type {
             Some(Ui(ui)) => ui,
             _ => continue,
         };
-        let encoding_symbol = &ui.payload[4..ui.payload.len()];
-        *bytes_received += encoding_symbol.len();
-        let rcv_tag = u16::from_be_bytes(ui.payload[0..2].try_into().unwrap());
-        if tag != rcv_tag {
+        let msg = match Message::decode(&ui.payload) {
+            Ok(msg) if [tag, *data_tag, tree_tag].contains(&msg.tag()) => {
+                authenticate(msg, trusted)?
+            }
+            _ => continue,
+        };
+        let (block, esi, encoding_symbol) = match msg {
+            Message::Data {
+                block, esi, symbol, ..
+            } => (block, esi, symbol),
+            Message::Error {
+                tag: rcv_tag,
+                reason,
+                candidates,
+            } if rcv_tag == tag => return Err(rejected(reason, &candidates)),
+            Message::Join {
+                tag: rcv_tag,
+                session,
+            } if rcv_tag == tag => {
+                info!("Sharing data with other stations, as session {}", session);
+                *data_tag = session;
+                continue;
+            }
+            Message::TreeReply {
+                tag: rcv_tag,
+                hash: rcv_hash,
+                first,
+                leaves,
+            } if rcv_tag == tree_tag => {
+                if let Some(v) = verifier.as_mut().filter(|v| v.hash == rcv_hash) {
+                    v.add_leaves(first, &leaves);
+                    *bytes_received = bytes_received.saturating_sub(v.check(decoders));
+                }
+                continue;
+            }
+            _ => continue,
+        };
+        let decoder = match decoders.get_mut(block as usize) {
+            Some(d) => d,
+            None => {
+                debug!("Got data for unknown block {}", block);
+                continue;
+            }
+        };
+        if !decoder.add(esi, &encoding_symbol) {
             continue;
         }
-        let esi = u16::from_be_bytes(ui.payload[2..4].try_into().unwrap());
+        save_symbol(spool, block, esi, &encoding_symbol);
+        *bytes_received += encoding_symbol.len();
+        if let Some(v) = verifier {
+            if decoders[block as usize].done() {
+                *bytes_received = bytes_received.saturating_sub(v.check(decoders));
+            }
+        }
 
         info!(
-            "Got id {} size {}: Total {} = {}%",
+            "Got block {} id {} size {}: Total {} = {}%",
+            block,
             esi,
             encoding_symbol.len(),
             bytes_received,
             100 * *bytes_received / size
         );
-
-        encoding_symbol_length = encoding_symbol.len();
-        decoder.push_encoding_symbol(encoding_symbol, esi as u32);
     }
-    Ok(encoding_symbol_length)
+    Ok(())
+}
+
+/// Bytes we have, as reported to the uploader when asking for more.
+///
+/// Incomplete blocks are never counted as complete, since the uploader uses
+/// this to decide how much more to send.
+fn existing_bytes(layout: &BlockLayout, decoders: &[BlockDecoder]) -> usize {
+    decoders
+        .iter()
+        .enumerate()
+        .map(|(n, d)| {
+            let len = layout.block_range(n).len();
+            if d.done() {
+                len
+            } else {
+                std::cmp::min(d.received, len.saturating_sub(d.encoding_symbol_length))
+            }
+        })
+        .sum()
 }
 
 /*
 * Request a block, until fully received.
 */
+#[allow(clippy::too_many_arguments)]
 async fn download_block(
     opt: &Opt,
     stream: &mut mpsc::Receiver<ax25ms::Frame>,
-    mut txclient: RouterServiceClient<tonic::transport::Channel>,
-    mut parser: Ax25ParserClient<tonic::transport::Channel>,
-    hash: &str,
-    size: usize,
-    source_block_size: usize,
+    mut txclient: Transport,
+    mut parser: Ax25Codec,
+    hash: &Hash,
+    meta: &Meta,
+    caps: &Capabilities,
+    trusted: Option<&[VerifyingKey]>,
+    early: Option<EarlyData>,
+    path: &str,
 ) -> Result<Vec<u8>, DownloaderError> {
-    let mut decoder = raptor_code::SourceBlockDecoder::new(source_block_size);
-    let tag = rand::thread_rng().gen::<u16>();
-    request_block(
-        &mut txclient,
-        &mut parser,
-        &opt.dst,
-        &opt.source,
-        hash,
-        tag,
-        0,
-    )
-    .await?;
-
+    let layout = &meta.layout;
+    let mut decoders: Vec<BlockDecoder> = (0..layout.blocks())
+        .map(|n| BlockDecoder::new(layout, n))
+        .collect();
     let mut bytes_done = 0_usize;
-    let len;
+    // SACKs name the data by its hash, and the uploader only knows
+    // whole files by hash.
+    let sack = caps.has(FEATURE_SACK) && meta.range.is_none() && meta.delta.is_none();
+    let expected = match (&meta.range, &meta.delta) {
+        (Some((_, h)), _) => *h,
+        (_, Some(d)) => d.hash,
+        _ => *hash,
+    };
+    let basis = meta.delta.as_ref().map(|d| (d.block_size, d.blocks));
+    // Chunk hashes are only to be had for whole files, and a file of one
+    // chunk is checked just as well at the end.
+    let mut verifier =
+        (caps.has(FEATURE_TREE) && expected == *hash && merkle::chunks(meta.original_size) > 1)
+            .then(|| Verifier::new(*hash, meta));
+    let tree_tag = rand::rng().random::<u16>();
+    let mut tree_retries = 0;
+    let mut spool = match Spool::open(path, &expected, layout, meta.compression) {
+        Ok((spool, symbols)) => {
+            for (block, esi, symbol) in symbols {
+                if let Some(d) = decoders.get_mut(block as usize) {
+                    if d.add(esi, &symbol) {
+                        bytes_done += symbol.len();
+                    }
+                }
+            }
+            if bytes_done > 0 {
+                info!("Resuming with {} bytes from {}", bytes_done, path);
+            }
+            Some(spool)
+        }
+        Err(e) => {
+            warn!(
+                "Can't use spool file {}, download won't be resumable: {}",
+                path, e
+            );
+            None
+        }
+    };
+    let mut requested = None;
+    if let Some(early) = early {
+        for (block, esi, symbol) in early.symbols {
+            if let Some(d) = decoders.get_mut(block as usize) {
+                if d.add(esi, &symbol) {
+                    save_symbol(&mut spool, block, esi, &symbol);
+                    bytes_done += symbol.len();
+                }
+            }
+        }
+        debug!("Already got {} bytes", bytes_done);
+        requested = early.tag.map(|tag| (tag, early.data_tag));
+    }
+    let (tag, mut data_tag) = match requested {
+        // Already requested, along with the metadata.
+        Some(tags) => tags,
+        None => {
+            // Only ask for what we don't already have.
+            let tag = rand::rng().random::<u16>();
+            if sack && decoders.iter().any(|d| !d.esis.is_empty()) {
+                request_sack(
+                    &mut txclient,
+                    &mut parser,
+                    &opt.dst,
+                    &opt.source,
+                    hash,
+                    tag,
+                    layout,
+                    &decoders,
+                    opt.codec(),
+                )
+                .await?;
+            } else {
+                request_block(
+                    &mut txclient,
+                    &mut parser,
+                    &opt.dst,
+                    &opt.source,
+                    opt.prefix(hash),
+                    tag,
+                    existing_bytes(layout, &decoders),
+                    opt.range,
+                    basis,
+                    opt.codec(),
+                )
+                .await?;
+            }
+            (tag, tag)
+        }
+    };
+    request_tree(&mut txclient, &mut parser, opt, hash, tree_tag, &verifier).await?;
+
     loop {
         match receive_streamed_block(
-            &mut decoder,
+            &mut decoders,
             stream,
             &mut parser,
             tag,
-            size,
+            &mut data_tag,
+            tree_tag,
+            &mut verifier,
+            &mut spool,
+            layout.size,
             &mut bytes_done,
             opt.packet_loss,
             opt.timeout,
+            trusted,
         )
         .await
         {
-            Ok(l) => {
-                len = l;
-                break;
+            Ok(()) => break,
+            Err(DownloaderError::Timeout) if decoders.iter().all(BlockDecoder::done) => {
+                tree_retries += 1;
+                if tree_retries > MAX_META_RETRIES {
+                    warn!("No chunk hashes, only checking the whole file");
+                    verifier = None;
+                } else {
+                    request_tree(&mut txclient, &mut parser, opt, hash, tree_tag, &verifier)
+                        .await?;
+                }
+                continue;
+            }
+            Err(DownloaderError::Timeout)
+                if !sack || decoders.iter().all(|d| d.esis.is_empty()) =>
+            {
+                debug!("Requesting again");
+                request_tree(&mut txclient, &mut parser, opt, hash, tree_tag, &verifier).await?;
+                request_block(
+                    &mut txclient,
+                    &mut parser,
+                    &opt.dst,
+                    &opt.source,
+                    opt.prefix(hash),
+                    tag,
+                    existing_bytes(layout, &decoders),
+                    opt.range,
+                    basis,
+                    opt.codec(),
+                )
+                .await?;
+                continue;
             }
             Err(DownloaderError::Timeout) => {
                 debug!("Requesting more");
-                request_block(
+                request_tree(&mut txclient, &mut parser, opt, hash, tree_tag, &verifier).await?;
+                request_sack(
                     &mut txclient,
                     &mut parser,
                     &opt.dst,
                     &opt.source,
                     hash,
                     tag,
-                    bytes_done,
+                    layout,
+                    &decoders,
+                    opt.codec(),
                 )
                 .await?;
                 continue;
@@ -218,23 +857,99 @@ async fn download_block(
         }
     }
     info!("Downloaded!");
-    let mut source_block = decoder.decode(len * source_block_size).expect("decode");
-    source_block.resize(size, 0); // Will only ever shrink.
+    let data = assemble(&expected, meta, &mut decoders);
+    // Done with the symbols, whether they were any good or not.
+    if spool.is_some() {
+        if let Err(e) = fs::remove_file(path) {
+            warn!("Failed to remove spool file {}: {}", path, e);
+        }
+    }
+    data
+}
+
+/// Where to save received symbols, so the download can be resumed.
+fn spool_path(output: Option<&str>, hash: &Hash) -> String {
+    match output {
+        Some(output) => format!("{output}.part"),
+        // The name from the uploader isn't known until after asking.
+        None => format!("{hash}.part"),
+    }
+}
+
+/// Save a received symbol to the spool file, if there is one.
+fn save_symbol(spool: &mut Option<Spool>, block: u16, esi: u16, symbol: &[u8]) {
+    if let Some(s) = spool {
+        if let Err(e) = s.add(block, esi, symbol) {
+            warn!(
+                "Failed to save to spool file, download won't be resumable: {}",
+                e
+            );
+            *spool = None;
+        }
+    }
+}
+
+/// The file, from fully specified decoders.
+fn assemble(
+    hash: &Hash,
+    meta: &Meta,
+    decoders: &mut [BlockDecoder],
+) -> Result<Vec<u8>, DownloaderError> {
+    let layout = &meta.layout;
+    let mut data = Vec::with_capacity(layout.size);
+    for (n, d) in decoders.iter_mut().enumerate() {
+        data.extend(
+            d.decode(layout.block_range(n).len())
+                .ok_or(DownloaderError::Decode(n))?,
+        );
+    }
+    if meta.compression != Compression::None {
+        data = meta
+            .compression
+            .decompress(&data, meta.original_size)
+            .map_err(DownloaderError::Decompress)?;
+        debug!(
+            "Decompressed {} bytes to {} with {}",
+            layout.size,
+            data.len(),
+            meta.compression
+        );
+    }
 
-    let digest = sha256::digest(&source_block[..]);
-    if digest != hash {
-        return Err(DownloaderError::ChecksumMismatch(digest, hash.to_string()));
+    let digest = merkle::hash(&data);
+    if digest != *hash {
+        return Err(DownloaderError::ChecksumMismatch(
+            digest.to_string(),
+            hash.to_string(),
+        ));
     }
-    Ok(source_block)
+    Ok(data)
 }
 
 #[derive(Debug)]
 enum DownloaderError {
     RPCError(tonic::transport::Error),
-    RPCStatusError(tonic::Status),
+    RPCStatusError(Box<tonic::Status>),
     StreamError(Box<dyn std::error::Error>),
     ChecksumMismatch(String, String),
+    Decode(usize),
+    Decompress(std::io::Error),
+    InvalidHash(String),
+    NoMatch(String),
+    Ambiguous(String, u64),
+    Unsupported(String),
+    Rejected(ErrorReason),
+    TrustedKeys(std::io::Error),
+    Basis(std::io::Error),
+    BadDelta(ProtocolError),
+    BadManifest(ProtocolError),
+    Output(std::io::Error),
+    Unsigned,
+    BadSignature,
     Timeout,
+
+    /// The router or TNC connection is gone.
+    StreamEnded,
 }
 impl From<Box<dyn std::error::Error>> for DownloaderError {
     fn from(error: Box<dyn std::error::Error>) -> Self {
@@ -248,7 +963,7 @@ impl From<tonic::transport::Error> for DownloaderError {
 }
 impl From<tonic::Status> for DownloaderError {
     fn from(error: tonic::Status) -> Self {
-        DownloaderError::RPCStatusError(error)
+        DownloaderError::RPCStatusError(Box::new(error))
     }
 }
 
@@ -259,39 +974,78 @@ impl std::fmt::Display for DownloaderError {
             Self::RPCStatusError(e) => write!(f, "RPC status Error: {e}"),
             Self::StreamError(e) => write!(f, "Stream Error: {e}"),
             Self::ChecksumMismatch(chk1, chk2) => write!(f, "Checksum Mismatch: {chk1} != {chk2}"),
+            Self::Decode(n) => write!(f, "Failed to decode block {n}"),
+            Self::Decompress(e) => write!(f, "Decompression failed: {e}"),
+            Self::InvalidHash(h) => write!(f, "Invalid hash: {h:?}"),
+            Self::NoMatch(name) => write!(f, "No file matches {name:?}"),
+            Self::Ambiguous(name, n) => write!(f, "{n} files match {name:?}"),
+            Self::Unsupported(what) => write!(f, "Uploader doesn't support {what}"),
+            Self::Rejected(reason) => write!(f, "Uploader rejected request: {reason}"),
+            Self::TrustedKeys(e) => write!(f, "Failed to load trusted keys: {e}"),
+            Self::Basis(e) => write!(f, "Failed to read older copy: {e}"),
+            Self::BadDelta(e) => write!(f, "Bad delta: {e}"),
+            Self::BadManifest(e) => write!(f, "Bad manifest: {e}"),
+            Self::Output(e) => write!(f, "Failed to write output: {e}"),
+            Self::Unsigned => write!(f, "Reply not signed"),
+            Self::BadSignature => write!(f, "Reply not signed by a trusted key"),
             Self::Timeout => write!(f, "Got timeout :-("),
+            Self::StreamEnded => write!(f, "Lost the connection to the router or TNC"),
         }
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn list(
     stream: &mut mpsc::Receiver<ax25ms::Frame>,
-    txclient: &mut RouterServiceClient<tonic::transport::Channel>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
     dst: &str,
     src: &str,
     timeout: f32,
-) -> Result<(), DownloaderError> {
-    let mut rng = rand::thread_rng();
-    let tag = rng.gen::<u16>();
-    let cmd = make_packet(parser, dst, src, format!("L {}", tag).to_string()).await?;
-    txclient
-        .send(tonic::Request::new(ax25ms::SendRequest {
-            frame: Some(ax25ms::Frame { payload: cmd }),
-        }))
-        .await?;
-    loop {
-        let frame = receive_frame(stream, timeout).await?;
+    codec: Codec,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<Vec<ListEntry>, DownloaderError> {
+    let tag = rand::rng().random::<u16>();
+    let mut pages: HashMap<u16, Vec<ListEntry>> = HashMap::new();
+    let mut last = None;
+    let mut retries = 0;
+    send_message(
+        txclient,
+        parser,
+        dst,
+        src,
+        &Message::List { tag, pages: vec![] },
+        codec,
+    )
+    .await?;
+    while last.is_none_or(|last| pages.len() <= last as usize) {
+        let frame = match receive_frame(stream, timeout).await {
+            Ok(frame) => frame,
+            Err(DownloaderError::Timeout) if retries < MAX_LIST_RETRIES => {
+                retries += 1;
+                // Until we know how many pages there are, ask for all of
+                // them.
+                let missing: Vec<u16> = match last {
+                    None => vec![],
+                    Some(last) => (0..=last)
+                        .filter(|p| !pages.contains_key(p))
+                        .take(MAX_LIST_PAGES_REQUEST)
+                        .collect(),
+                };
+                debug!("Requesting list pages {:?}", missing);
+                let msg = Message::List {
+                    tag,
+                    pages: missing,
+                };
+                send_message(txclient, parser, dst, src, &msg, codec).await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
         debug!("List got some frame");
-        let parsed = parser
-            .parse(tonic::Request::new(ax25::ParseRequest {
-                payload: frame,
-                check_fcs: true,
-            }))
-            .await?
-            .into_inner()
-            .packet
-            .expect("surely the RPC reply has a packet");
+        let Some(parsed) = parser.parse(frame).await? else {
+            continue;
+        };
         let ui = match parsed.frame_type {
             Some(ax25::packet::FrameType::Ui(ui)) => ui,
             _ => {
@@ -299,121 +1053,1127 @@ async fn list(
                 continue;
             }
         };
-        let reply = match std::str::from_utf8(&ui.payload) {
-            Ok(x) => x,
+        let msg = match Message::decode(&ui.payload) {
+            Ok(msg) if msg.tag() == tag => authenticate(msg, trusted)?,
             _ => {
-                debug!("Not UTF8");
+                debug!("Wrong tag");
                 continue;
             }
         };
-        if reply == format!("l {}", tag) {
-            return Ok(());
-        }
-        let m = match LIST_REPLY_RE.captures(reply) {
-            Some(x) => x,
-            None => {
+        match msg {
+            Message::ListReply {
+                page,
+                last: rcv_last,
+                entries,
+                ..
+            } => {
+                debug!("Got list page {}/{}", page, rcv_last);
+                last = Some(rcv_last);
+                if page <= rcv_last && pages.insert(page, entries).is_none() {
+                    retries = 0;
+                }
+            }
+            _ => {
                 debug!("Not a list reply");
                 continue;
             }
         };
-        if m[1] != format!("{}", tag) {
-            debug!("Wrong tag");
-            continue;
-        }
-        let list = reply.lines().collect::<Vec<&str>>();
-        println!("List reply:\n");
-        for entry in &list[1..] {
-            println!("{:?}", entry);
-        }
     }
+    let mut pages: Vec<_> = pages.into_iter().collect();
+    pages.sort_by_key(|(page, _)| *page);
+    Ok(pages.into_iter().flat_map(|(_, entries)| entries).collect())
 }
 
-async fn get_meta(
+/// Prefix of a file argument to look up by name.
+const NAME_PREFIX: &str = "name:";
+
+/// Ask the uploader which files match `pattern`. Returns how many do,
+/// and as many of them as it sent.
+#[allow(clippy::too_many_arguments)]
+async fn find(
     stream: &mut mpsc::Receiver<ax25ms::Frame>,
-    txclient: &mut RouterServiceClient<tonic::transport::Channel>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
-    dst: &str,
-    src: &str,
-    hash: &str,
-    timeout: f32,
-) -> Result<(usize, usize), DownloaderError> {
-    let cmd = make_packet(parser, dst, src, format!("M {}", hash).to_string()).await?;
-    txclient
-        .send(tonic::Request::new(ax25ms::SendRequest {
-            frame: Some(ax25ms::Frame { payload: cmd }),
-        }))
-        .await?;
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    pattern: &str,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(u64, Vec<FindEntry>), DownloaderError> {
+    let tag = rand::rng().random::<u16>();
+    let msg = Message::Find {
+        tag,
+        pattern: pattern.to_string(),
+    };
+    let mut retries = 0;
+    send_message(txclient, parser, &opt.dst, &opt.source, &msg, opt.codec()).await?;
     loop {
-        let frame = receive_frame(stream, timeout).await?;
-        let parsed = parser
-            .parse(tonic::Request::new(ax25::ParseRequest {
-                payload: frame,
-                check_fcs: true,
-            }))
-            .await?
-            .into_inner()
-            .packet
-            .expect("surely the RPC reply has a packet");
-        let ui = match parsed.frame_type {
-            Some(ax25::packet::FrameType::Ui(ui)) => ui,
-            _ => continue,
-        };
-        let reply = match std::str::from_utf8(&ui.payload) {
-            Ok(x) => x,
-            _ => {
+        let frame = match receive_frame(stream, opt.timeout).await {
+            Ok(frame) => frame,
+            Err(DownloaderError::Timeout) if retries < MAX_LIST_RETRIES => {
+                retries += 1;
+                debug!("Resending find request");
+                send_message(txclient, parser, &opt.dst, &opt.source, &msg, opt.codec()).await?;
                 continue;
             }
+            Err(e) => return Err(e),
         };
-        let m = match META_REPLY_RE.captures(reply) {
-            Some(x) => x,
-            None => continue,
-        };
-        if m[1] != *hash {
+        let Some(parsed) = parser.parse(frame).await? else {
             continue;
-        }
-        let block = match m[2].parse::<usize>() {
-            Ok(x) => x,
-            _ => continue,
         };
-        let size = match m[3].parse::<usize>() {
-            Ok(x) => x,
+        let ui = match parsed.frame_type {
+            Some(ax25::packet::FrameType::Ui(ui)) => ui,
             _ => continue,
         };
-        return Ok((block, size));
+        match Message::decode(&ui.payload) {
+            Ok(msg) if msg.tag() == tag => match authenticate(msg, trusted)? {
+                Message::FindReply { total, entries, .. } => return Ok((total, entries)),
+                Message::Error { reason, .. } => return Err(DownloaderError::Rejected(reason)),
+                _ => debug!("Not a find reply"),
+            },
+            _ => debug!("Wrong tag"),
+        }
     }
 }
 
-lazy_static! {
-    static ref META_REPLY_RE: Regex = Regex::new(r"m (\w+) (\d+) (\d+)").unwrap();
-    static ref LIST_REPLY_RE: Regex = Regex::new(r"(?m)l (\d+)\n.*").unwrap();
+/*
+* The hash of the file to get: either given as is, as a prefix of it, or
+* looked up by name if given as `name:PATTERN`, in which case exactly one
+* file must match.
+*/
+async fn resolve(
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    arg: &str,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<Hash, DownloaderError> {
+    let Some(pattern) = arg.strip_prefix(NAME_PREFIX) else {
+        if let Some(hash) = Hash::from_hex(arg) {
+            return Ok(hash);
+        }
+        // The uploader knows the rest.
+        let prefix =
+            HashPrefix::from_hex(arg).ok_or(DownloaderError::InvalidHash(arg.to_string()))?;
+        let (hash, _) = get_meta(
+            stream,
+            txclient,
+            parser,
+            &opt.dst,
+            &opt.source,
+            &prefix,
+            opt.timeout,
+            opt.codec(),
+            trusted,
+        )
+        .await?;
+        info!("{} is {}", prefix, hash);
+        return Ok(hash);
+    };
+    let (total, entries) = find(stream, txclient, parser, opt, pattern, trusted).await?;
+    match (total, entries.as_slice()) {
+        (0, _) => Err(DownloaderError::NoMatch(pattern.to_string())),
+        (1, [entry]) => {
+            info!("{} is {} ({} bytes)", entry.name, entry.hash, entry.size);
+            Ok(entry.hash)
+        }
+        _ => {
+            for entry in &entries {
+                info!("Matches: {} {} {}", entry.hash, entry.size, entry.name);
+            }
+            Err(DownloaderError::Ambiguous(pattern.to_string(), total))
+        }
+    }
+}
+
+/// What the uploader said about a file.
+struct Meta {
+    layout: BlockLayout,
+    compression: Compression,
+    original_size: usize,
+    info: FileInfo,
+
+    /// Offset and hash, if this is a range of the file.
+    range: Option<(u64, Hash)>,
+
+    /// What the delta is, if this is the differences from an older copy.
+    delta: Option<Delta>,
+}
+
+/// A delta being downloaded, from a delta reply.
+struct Delta {
+    /// Size of the file the delta makes.
+    file_size: u64,
+
+    /// Hash of the delta itself.
+    hash: Hash,
+
+    /// What the signatures sent were of.
+    block_size: u64,
+    blocks: u64,
+}
+
+/// An older copy of the file being downloaded.
+struct Basis {
+    data: Vec<u8>,
+    block_size: usize,
 }
 
-fn start_stream(
-    mut client: RouterServiceClient<tonic::transport::Channel>,
-) -> tokio::sync::mpsc::Receiver<ax25ms::Frame> {
-    let (tx, rx) = mpsc::channel(32);
-    tokio::spawn(async move {
-        let mut stream = client
-            .stream_frames(StreamRequest {})
-            .await
-            .unwrap()
-            .into_inner();
-        while let Some(item) = stream.next().await {
-            tx.send(item.unwrap()).await.unwrap();
+impl Basis {
+    fn read(path: &str) -> Result<Basis, DownloaderError> {
+        let data = fs::read(path).map_err(DownloaderError::Basis)?;
+        let block_size = delta::block_size(data.len());
+        Ok(Basis { data, block_size })
+    }
+
+    /// Basis messages with all signatures, each within `size` bytes.
+    fn messages(&self, tag: u16, hash: &Hash, size: usize, codec: Codec) -> Vec<Message> {
+        let basis = |first: usize, sigs| Message::Basis {
+            tag,
+            hash: *hash,
+            block_size: self.block_size as u64,
+            first: first as u64,
+            sigs,
+        };
+        let mut msgs = Vec::new();
+        let mut first = 0;
+        let mut sigs = Vec::new();
+        for sig in delta::signatures(&self.data, self.block_size) {
+            sigs.push(sig);
+            if sigs.len() > 1 && basis(first, sigs.clone()).encode(codec).len() > size {
+                let last = sigs.pop().unwrap();
+                let n = sigs.len();
+                msgs.push(basis(first, std::mem::replace(&mut sigs, vec![last])));
+                first += n;
+            }
+        }
+        if !sigs.is_empty() {
+            msgs.push(basis(first, sigs));
         }
+        msgs
+    }
+
+    fn blocks(&self) -> u64 {
+        (self.data.len() / self.block_size) as u64
+    }
+}
+
+/// Send the signatures of `basis`, and a request for the differences
+/// from it along with the metadata.
+#[allow(clippy::too_many_arguments)]
+async fn request_delta(
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    hash: &Hash,
+    basis: &Basis,
+    caps: &Capabilities,
+    tag: u16,
+) -> Result<(), DownloaderError> {
+    let mut msgs = basis.messages(tag, hash, caps.max_packet_size, opt.codec());
+    info!(
+        "Sending {} signatures of the older copy in {} frames",
+        basis.blocks(),
+        msgs.len()
+    );
+    msgs.push(Message::Get {
+        tag,
+        frequency: 0,
+        existing: 0,
+        hash: opt.prefix(hash),
+        meta: true,
+        range: None,
+        delta: Some((basis.block_size as u64, basis.blocks())),
     });
-    rx
+    for msg in &msgs {
+        send_message(txclient, parser, &opt.dst, &opt.source, msg, opt.codec()).await?;
+    }
+    Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<(), DownloaderError> {
-    let opt = {
-        let mut opt = Opt::parse();
-        if opt.txrouter == "" {
-            opt.txrouter = opt.router.clone();
+/*
+* Send the signatures of `basis` and ask for the differences from it,
+* along with the metadata. Returns the metadata with what the delta is,
+* and the request's tag, that the data will be sent with.
+*/
+#[allow(clippy::too_many_arguments)]
+async fn get_delta(
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    hash: &Hash,
+    basis: &Basis,
+    caps: &Capabilities,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(Meta, EarlyData), DownloaderError> {
+    let mut tag = rand::rng().random::<u16>();
+    let mut info = FileInfo::default();
+    let mut retries = 0;
+    request_delta(txclient, parser, opt, hash, basis, caps, tag).await?;
+    loop {
+        let frame = match receive_frame(stream, opt.timeout).await {
+            Ok(frame) => frame,
+            Err(DownloaderError::Timeout) if retries < MAX_META_RETRIES => {
+                retries += 1;
+                debug!("Requesting delta again");
+                // With a new tag, since the uploader may have more
+                // signatures this time, and so send a different delta.
+                tag = rand::rng().random::<u16>();
+                request_delta(txclient, parser, opt, hash, basis, caps, tag).await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(parsed) = parser.parse(frame).await? else {
+            continue;
+        };
+        let ui = match parsed.frame_type {
+            Some(ax25::packet::FrameType::Ui(ui)) => ui,
+            _ => continue,
+        };
+        let msg = match Message::decode(&ui.payload) {
+            Ok(msg) if msg.tag() == tag => authenticate(msg, trusted)?,
+            _ => continue,
+        };
+        match msg {
+            Message::MetaReply {
+                hash: rcv_hash,
+                info: rcv_info,
+                ..
+            } if rcv_hash == *hash => info = rcv_info,
+            Message::DeltaReply {
+                hash: rcv_hash,
+                file_size,
+                delta_hash,
+                layout,
+                compression,
+                original_size,
+                ..
+            } if rcv_hash == *hash => {
+                let meta = Meta {
+                    layout,
+                    compression,
+                    original_size,
+                    info,
+                    range: None,
+                    delta: Some(Delta {
+                        file_size,
+                        hash: delta_hash,
+                        block_size: basis.block_size as u64,
+                        blocks: basis.blocks(),
+                    }),
+                };
+                // The data follows, for this tag.
+                let early = EarlyData {
+                    tag: Some(tag),
+                    data_tag: tag,
+                    symbols: Vec::new(),
+                };
+                return Ok((meta, early));
+            }
+            Message::Error {
+                reason, candidates, ..
+            } => return Err(rejected(reason, &candidates)),
+            _ => continue,
         }
-        opt
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn get_meta(
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    dst: &str,
+    src: &str,
+    hash: &HashPrefix,
+    timeout: f32,
+    codec: Codec,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(Hash, Meta), DownloaderError> {
+    let tag = rand::rng().random::<u16>();
+    let msg = Message::Meta {
+        tag,
+        hash: hash.clone(),
     };
+    send_message(txclient, parser, dst, src, &msg, codec).await?;
+    let mut retries = 0;
+    loop {
+        let frame = match receive_frame(stream, timeout).await {
+            Ok(frame) => frame,
+            Err(DownloaderError::Timeout) if retries < MAX_META_RETRIES => {
+                retries += 1;
+                debug!("Requesting metadata again");
+                send_message(txclient, parser, dst, src, &msg, codec).await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(parsed) = parser.parse(frame).await? else {
+            continue;
+        };
+        let ui = match parsed.frame_type {
+            Some(ax25::packet::FrameType::Ui(ui)) => ui,
+            _ => continue,
+        };
+        let msg = match Message::decode(&ui.payload) {
+            Ok(msg) if msg.tag() == tag => authenticate(msg, trusted)?,
+            _ => continue,
+        };
+        match msg {
+            Message::MetaReply {
+                hash: rcv_hash,
+                layout,
+                compression,
+                original_size,
+                info,
+                ..
+            } if hash.matches(&rcv_hash) => {
+                return Ok((
+                    rcv_hash,
+                    Meta {
+                        layout,
+                        compression,
+                        original_size,
+                        info,
+                        range: None,
+                        delta: None,
+                    },
+                ))
+            }
+            Message::Error {
+                reason, candidates, ..
+            } => return Err(rejected(reason, &candidates)),
+            _ => continue,
+        }
+    }
+}
+
+/// Error for an error reply, listing what an ambiguous hash prefix could
+/// be.
+fn rejected(reason: ErrorReason, candidates: &[Hash]) -> DownloaderError {
+    for hash in candidates {
+        info!("Could be {}", hash);
+    }
+    DownloaderError::Rejected(reason)
+}
+
+/// Unwrap a possibly signed reply.
+///
+/// With trusted keys every reply must be signed by one of them, or it's a
+/// hard error. Data frames aren't signed, since the data is checked
+/// against the hash.
+fn authenticate(
+    msg: Message,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<Message, DownloaderError> {
+    match (msg, trusted) {
+        (Message::Signed { signature, message }, Some(keys)) => {
+            if !verify(&message, &signature, keys) {
+                return Err(DownloaderError::BadSignature);
+            }
+            Ok(*message)
+        }
+        (Message::Signed { message, .. }, None) => Ok(*message),
+        (msg @ (Message::Data { .. } | Message::TreeReply { .. }), _) | (msg, None) => Ok(msg),
+        (_, Some(_)) => Err(DownloaderError::Unsigned),
+    }
+}
+
+/*
+* Exchange capabilities with the uploader, and return what we have in
+* common. An uploader that doesn't reply is assumed to only support the
+* baseline.
+*/
+#[allow(clippy::too_many_arguments)]
+async fn get_caps(
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    dst: &str,
+    src: &str,
+    ours: &Capabilities,
+    timeout: f32,
+    codec: Codec,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<Capabilities, DownloaderError> {
+    let tag = rand::rng().random::<u16>();
+    let msg = Message::Caps { tag, caps: *ours };
+    send_message(txclient, parser, dst, src, &msg, codec).await?;
+    loop {
+        let frame = match receive_frame(stream, timeout).await {
+            Ok(frame) => frame,
+            Err(DownloaderError::Timeout) => {
+                warn!("No capabilities reply, assuming baseline");
+                return Ok(ours.common(&Capabilities::baseline()));
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(parsed) = parser.parse(frame).await? else {
+            continue;
+        };
+        let ui = match parsed.frame_type {
+            Some(ax25::packet::FrameType::Ui(ui)) => ui,
+            _ => continue,
+        };
+        let msg = match Message::decode(&ui.payload) {
+            Ok(msg) if msg.tag() == tag => authenticate(msg, trusted)?,
+            _ => continue,
+        };
+        match msg {
+            Message::CapsReply { caps, .. } => {
+                debug!("Uploader capabilities: {:?}", caps);
+                return Ok(ours.common(&caps));
+            }
+            _ => continue,
+        }
+    }
+}
+
+/*
+* Send capabilities and a GM request back to back, so that the transfer
+* starts without waiting for either reply. Returns the common
+* capabilities, the metadata (from the metadata reply, or a header frame
+* if that was lost), and any data that arrived before it.
+*/
+async fn fast_start(
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    txclient: &mut Transport,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    hash: &Hash,
+    ours: &Capabilities,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(Capabilities, Option<Meta>, EarlyData), DownloaderError> {
+    let caps_tag = rand::rng().random::<u16>();
+    let tag = caps_tag.wrapping_add(1);
+    let msgs = [
+        Message::Caps {
+            tag: caps_tag,
+            caps: *ours,
+        },
+        Message::Get {
+            tag,
+            frequency: 0,
+            existing: 0,
+            hash: opt.prefix(hash),
+            meta: true,
+            range: opt.range,
+            delta: None,
+        },
+    ];
+    for msg in &msgs {
+        send_message(txclient, parser, &opt.dst, &opt.source, msg, opt.codec()).await?;
+    }
+    let mut caps = None;
+    let mut early = EarlyData {
+        tag: Some(tag),
+        data_tag: tag,
+        symbols: Vec::new(),
+    };
+    let mut rng = rand::rng();
+    let meta = loop {
+        let frame = match receive_frame(stream, opt.timeout).await {
+            Ok(frame) => frame,
+            Err(DownloaderError::Timeout) => {
+                warn!("No metadata reply, requesting it separately");
+                break None;
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(parsed) = parser.parse(frame).await? else {
+            continue;
+        };
+        let ui = match parsed.frame_type {
+            Some(ax25::packet::FrameType::Ui(ui)) => ui,
+            _ => continue,
+        };
+        let msg = match Message::decode(&ui.payload) {
+            Ok(msg) if [tag, caps_tag, early.data_tag].contains(&msg.tag()) => {
+                authenticate(msg, trusted)?
+            }
+            _ => continue,
+        };
+        match msg {
+            Message::CapsReply {
+                tag: rcv_tag,
+                caps: c,
+            } if rcv_tag == caps_tag => {
+                debug!("Uploader capabilities: {:?}", c);
+                caps = Some(ours.common(&c));
+            }
+            Message::MetaReply {
+                tag: rcv_tag,
+                hash: rcv_hash,
+                layout,
+                compression,
+                original_size,
+                info,
+            } if rcv_tag == tag && rcv_hash == *hash => {
+                break Some(Meta {
+                    layout,
+                    compression,
+                    original_size,
+                    info,
+                    range: None,
+                    delta: None,
+                });
+            }
+            Message::RangeReply {
+                tag: rcv_tag,
+                hash: rcv_hash,
+                offset,
+                range_hash,
+                layout,
+                compression,
+                original_size,
+            } if rcv_tag == tag && rcv_hash == *hash && opt.range.is_some() => {
+                break Some(Meta {
+                    layout,
+                    compression,
+                    original_size,
+                    info: FileInfo::default(),
+                    range: Some((offset, range_hash)),
+                    delta: None,
+                });
+            }
+            Message::Header {
+                tag: rcv_tag,
+                hash: rcv_hash,
+                layout,
+                compression,
+                original_size,
+            } if (rcv_tag == tag || rcv_tag == early.data_tag) && rcv_hash == *hash => {
+                warn!("Missed the metadata reply, using header frame");
+                break Some(Meta {
+                    layout,
+                    compression,
+                    original_size,
+                    info: FileInfo::default(),
+                    range: None,
+                    delta: None,
+                });
+            }
+            Message::Data {
+                tag: rcv_tag,
+                block,
+                esi,
+                symbol,
+            } if rcv_tag == tag || rcv_tag == early.data_tag => {
+                if rng.random::<f32>() >= opt.packet_loss {
+                    early.symbols.push((block, esi, symbol));
+                }
+            }
+            Message::Error {
+                tag: rcv_tag,
+                reason,
+                candidates,
+            } if rcv_tag == tag => return Err(rejected(reason, &candidates)),
+            Message::Join {
+                tag: rcv_tag,
+                session,
+            } if rcv_tag == tag => {
+                info!("Sharing data with other stations, as session {}", session);
+                early.data_tag = session;
+            }
+            _ => continue,
+        }
+    };
+    let caps = caps.unwrap_or_else(|| {
+        warn!("No capabilities reply, assuming baseline");
+        ours.common(&Capabilities::baseline())
+    });
+    Ok((caps, meta, early))
+}
+
+/*
+* Listen to other stations' transfers of `hash`, collecting their data
+* frames, until nothing about it has been heard for `--listen` seconds,
+* or we have the whole file.
+*
+* Tags are tied to the hash by the requests and replies that mention
+* both. Returns the data, and the file being decoded from it if we heard
+* its parameters.
+*/
+async fn harvest(
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    hash: &Hash,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(Option<Session>, EarlyData), DownloaderError> {
+    info!("Listening for other transfers of {}…", hash);
+    let mut tags = HashSet::new();
+    let mut pending = Pending::new();
+    let mut session: Option<Session> = None;
+    let mut early = EarlyData {
+        tag: None,
+        data_tag: 0,
+        symbols: Vec::new(),
+    };
+    let mut rng = rand::rng();
+    let mut deadline = std::time::Instant::now() + Duration::from_secs_f32(opt.listen);
+    while !session.as_ref().is_some_and(Session::done) {
+        let left = deadline.saturating_duration_since(std::time::Instant::now());
+        let frame = match receive_frame(stream, left.as_secs_f32()).await {
+            Ok(frame) => frame,
+            Err(DownloaderError::Timeout) => break,
+            Err(e) => return Err(e),
+        };
+        let Some(parsed) = parser.parse(frame).await? else {
+            continue;
+        };
+        let ui = match parsed.frame_type {
+            Some(ax25::packet::FrameType::Ui(ui)) => ui,
+            _ => continue,
+        };
+        // With trusted keys, unsigned requests are ignored too, so only
+        // the uploader can tie a tag to the hash.
+        let msg = match Message::decode(&ui.payload).map(|m| authenticate(m, trusted)) {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(e)) => {
+                debug!("Ignoring frame: {}", e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let (tag, meta) = match msg {
+            // Range and delta requests get other data with their tag.
+            Message::Get {
+                tag,
+                hash: rcv_hash,
+                range: None,
+                delta: None,
+                ..
+            }
+            | Message::Meta {
+                tag,
+                hash: rcv_hash,
+            } if rcv_hash.matches(hash) => (tag, None),
+            Message::Sack {
+                tag,
+                hash: rcv_hash,
+                ..
+            } if rcv_hash == *hash => (tag, None),
+            Message::MetaReply {
+                tag,
+                hash: rcv_hash,
+                layout,
+                compression,
+                original_size,
+                info,
+            } if rcv_hash == *hash => (
+                tag,
+                Some(Meta {
+                    layout,
+                    compression,
+                    original_size,
+                    info,
+                    range: None,
+                    delta: None,
+                }),
+            ),
+            Message::Header {
+                tag,
+                hash: rcv_hash,
+                layout,
+                compression,
+                original_size,
+            } if rcv_hash == *hash => (
+                tag,
+                Some(Meta {
+                    layout,
+                    compression,
+                    original_size,
+                    info: FileInfo::default(),
+                    range: None,
+                    delta: None,
+                }),
+            ),
+            Message::Join { tag, session } if tags.contains(&tag) => (session, None),
+            Message::Data {
+                tag,
+                block,
+                esi,
+                symbol,
+            } => {
+                if rng.random::<f32>() < opt.packet_loss {
+                    continue;
+                }
+                if !tags.contains(&tag) {
+                    pending.add(tag, block, esi, symbol);
+                    continue;
+                }
+                if let Some(session) = &mut session {
+                    session.add(block, esi, &symbol);
+                }
+                early.symbols.push((block, esi, symbol));
+                deadline = std::time::Instant::now() + Duration::from_secs_f32(opt.listen);
+                continue;
+            }
+            _ => continue,
+        };
+        deadline = std::time::Instant::now() + Duration::from_secs_f32(opt.listen);
+        if tags.insert(tag) {
+            debug!("Tag {} is for {}", tag, hash);
+            let symbols = pending.remove(tag);
+            if let Some(s) = &mut session {
+                for (block, esi, symbol) in &symbols {
+                    s.add(*block, *esi, symbol);
+                }
+            }
+            early.symbols.extend(symbols);
+        }
+        match (&mut session, meta) {
+            (None, Some(meta)) => {
+                info!("Heard a transfer of {} bytes", meta.original_size);
+                let mut s = Session::new(*hash, meta);
+                for (block, esi, symbol) in &early.symbols {
+                    s.add(*block, *esi, symbol);
+                }
+                session = Some(s);
+            }
+            (Some(s), Some(meta)) if s.meta.info.name.is_empty() => s.meta.info = meta.info,
+            _ => {}
+        }
+    }
+    info!("Heard {} data frames for {}", early.symbols.len(), hash);
+    Ok((session, early))
+}
+
+/// Write a downloaded range into the output file, creating it if needed.
+fn write_range(path: &str, offset: u64, data: &[u8]) -> std::io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+    let mut f = fs::File::options()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    f.seek(SeekFrom::Start(offset))?;
+    f.write_all(data)?;
+    info!("Wrote {} bytes at {} of {}", data.len(), offset, path);
+    Ok(())
+}
+
+/// Write a downloaded file, and set its mtime.
+fn write_output(path: &str, data: &[u8], info: &FileInfo) -> std::io::Result<()> {
+    fs::write(path, data)?;
+    info!("Wrote {}", path);
+    // Unknown if we only got header frames.
+    if info.mtime != 0 {
+        let mtime = std::time::UNIX_EPOCH + Duration::from_secs(info.mtime);
+        if let Err(e) = fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|f| f.set_modified(mtime))
+        {
+            warn!("Failed to set mtime of {}: {}", path, e);
+        }
+    }
+    Ok(())
+}
+
+/// Max encoding symbols to keep for a tag before knowing what file it's
+/// for.
+const MAX_PENDING_SYMBOLS: usize = 4096;
+
+/// Max bytes of encoding symbols to keep for all tags together. The tags
+/// heard from the longest ago are dropped to make room.
+const MAX_PENDING_BYTES: usize = 16 << 20;
+
+/// How long to keep symbols for a tag, or a file being received
+/// passively, after last hearing anything for it.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(900);
+
+/// Encoding symbols of one tag, received before knowing what file they're
+/// for.
+struct PendingTag {
+    symbols: Vec<(u16, u16, Vec<u8>)>,
+    last_seen: Instant,
+}
+
+/// Encoding symbols per tag, received before knowing what file they're for.
+#[derive(Default)]
+struct Pending {
+    tags: HashMap<u16, PendingTag>,
+
+    /// Bytes of symbols in `tags`.
+    bytes: usize,
+}
+
+impl Pending {
+    fn new() -> Pending {
+        Pending::default()
+    }
+
+    fn add(&mut self, tag: u16, block: u16, esi: u16, symbol: Vec<u8>) {
+        while self.bytes + symbol.len() > MAX_PENDING_BYTES {
+            let Some(oldest) = self
+                .tags
+                .iter()
+                .min_by_key(|(_, t)| t.last_seen)
+                .map(|(tag, _)| *tag)
+            else {
+                return;
+            };
+            debug!("Dropping symbols of tag {} to make room", oldest);
+            self.remove(oldest);
+        }
+        let t = self.tags.entry(tag).or_insert_with(|| PendingTag {
+            symbols: Vec::new(),
+            last_seen: Instant::now(),
+        });
+        t.last_seen = Instant::now();
+        if t.symbols.len() < MAX_PENDING_SYMBOLS {
+            self.bytes += symbol.len();
+            t.symbols.push((block, esi, symbol));
+        }
+    }
+
+    /// Take the symbols of `tag`.
+    fn remove(&mut self, tag: u16) -> Vec<(u16, u16, Vec<u8>)> {
+        let symbols = self.tags.remove(&tag).map_or(Vec::new(), |t| t.symbols);
+        self.bytes -= symbols.iter().map(|(_, _, s)| s.len()).sum::<usize>();
+        symbols
+    }
+
+    /// Drop tags not heard from for `IDLE_TIMEOUT`.
+    fn expire(&mut self) {
+        let idle: Vec<u16> = self
+            .tags
+            .iter()
+            .filter(|(_, t)| t.last_seen.elapsed() > IDLE_TIMEOUT)
+            .map(|(tag, _)| *tag)
+            .collect();
+        for tag in idle {
+            self.remove(tag);
+        }
+    }
+}
+
+/// A file being received passively.
+struct Session {
+    hash: Hash,
+    meta: Meta,
+    decoders: Vec<BlockDecoder>,
+
+    /// When anything for the file was last heard.
+    last_seen: Instant,
+}
+
+impl Session {
+    fn new(hash: Hash, meta: Meta) -> Session {
+        let decoders = (0..meta.layout.blocks())
+            .map(|n| BlockDecoder::new(&meta.layout, n))
+            .collect();
+        Session {
+            hash,
+            meta,
+            decoders,
+            last_seen: Instant::now(),
+        }
+    }
+
+    fn done(&self) -> bool {
+        self.decoders.iter().all(BlockDecoder::done)
+    }
+
+    fn add(&mut self, block: u16, esi: u16, symbol: &[u8]) {
+        self.last_seen = Instant::now();
+        if let Some(d) = self.decoders.get_mut(block as usize) {
+            d.add(esi, symbol);
+        }
+    }
+
+    /// Assemble the file, and write it to `dir`.
+    fn write(&mut self, dir: &str) -> Result<(), DownloaderError> {
+        let data = assemble(&self.hash, &self.meta, &mut self.decoders)?;
+        let name = output_name(&self.meta.info.name, &self.hash);
+        let path = std::path::Path::new(dir).join(name);
+        write_output(&path.to_string_lossy(), &data, &self.meta.info)
+            .map_err(DownloaderError::Output)
+    }
+}
+
+/*
+* Collect encoding symbols for every file that's announced with a
+* metadata reply or header frame, without requesting anything, and write
+* the ones that can be decoded to `dir`. Runs until the stream ends.
+*/
+async fn passive(
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    dir: &str,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(), DownloaderError> {
+    info!("Listening for files…");
+    let mut sessions: HashMap<u16, Session> = HashMap::new();
+    let mut pending = Pending::new();
+    let mut complete: HashSet<Hash> = HashSet::new();
+    let mut rng = rand::rng();
+    let mut expired = Instant::now();
+    while let Some(frame) = stream.recv().await {
+        // Files that stopped being sent will never finish.
+        if expired.elapsed() > IDLE_TIMEOUT / 10 {
+            expired = Instant::now();
+            pending.expire();
+            sessions.retain(|tag, s| {
+                let keep = s.last_seen.elapsed() <= IDLE_TIMEOUT;
+                if !keep {
+                    info!("Giving up on {} with tag {}", s.hash, tag);
+                }
+                keep
+            });
+        }
+        let Some(parsed) = parser.parse(frame.payload).await? else {
+            continue;
+        };
+        let ui = match parsed.frame_type {
+            Some(ax25::packet::FrameType::Ui(ui)) => ui,
+            _ => continue,
+        };
+        // Anyone can send anything, so bad frames aren't fatal here.
+        let msg = match Message::decode(&ui.payload).map(|m| authenticate(m, trusted)) {
+            Ok(Ok(msg)) => msg,
+            Ok(Err(e)) => {
+                debug!("Ignoring frame: {}", e);
+                continue;
+            }
+            Err(_) => continue,
+        };
+        let tag = match msg {
+            Message::MetaReply {
+                tag,
+                hash,
+                layout,
+                compression,
+                original_size,
+                info,
+            } => {
+                let meta = Meta {
+                    layout,
+                    compression,
+                    original_size,
+                    info,
+                    range: None,
+                    delta: None,
+                };
+                announce(
+                    &mut sessions,
+                    &mut pending,
+                    &complete,
+                    tag,
+                    hash,
+                    meta,
+                    true,
+                );
+                tag
+            }
+            Message::Header {
+                tag,
+                hash,
+                layout,
+                compression,
+                original_size,
+            } => {
+                let meta = Meta {
+                    layout,
+                    compression,
+                    original_size,
+                    info: FileInfo::default(),
+                    range: None,
+                    delta: None,
+                };
+                announce(
+                    &mut sessions,
+                    &mut pending,
+                    &complete,
+                    tag,
+                    hash,
+                    meta,
+                    false,
+                );
+                tag
+            }
+            Message::Data {
+                tag,
+                block,
+                esi,
+                symbol,
+            } => {
+                if rng.random::<f32>() < opt.packet_loss {
+                    continue;
+                }
+                match sessions.get_mut(&tag) {
+                    Some(session) => session.add(block, esi, &symbol),
+                    None => pending.add(tag, block, esi, symbol),
+                }
+                tag
+            }
+            _ => continue,
+        };
+        if !sessions.get(&tag).is_some_and(Session::done) {
+            continue;
+        }
+        let mut session = sessions.remove(&tag).expect("just checked");
+        match session.write(dir) {
+            Ok(()) => {
+                complete.insert(session.hash);
+            }
+            Err(e) => warn!("Failed to receive {}: {}", session.hash, e),
+        }
+    }
+    Err(DownloaderError::StreamEnded)
+}
+
+/// Start receiving a file announced by a META reply or header frame.
+fn announce(
+    sessions: &mut HashMap<u16, Session>,
+    pending: &mut Pending,
+    complete: &HashSet<Hash>,
+    tag: u16,
+    hash: Hash,
+    meta: Meta,
+    has_info: bool,
+) {
+    if complete.contains(&hash) {
+        return;
+    }
+    if let Some(session) = sessions.get_mut(&tag) {
+        if session.hash == hash {
+            session.last_seen = Instant::now();
+            if has_info {
+                session.meta.info = meta.info;
+            }
+            return;
+        }
+    }
+    info!("Receiving {} ({} bytes)", hash, meta.original_size);
+    let mut session = Session::new(hash, meta);
+    for (block, esi, symbol) in pending.remove(tag) {
+        session.add(block, esi, &symbol);
+    }
+    sessions.insert(tag, session);
+}
+
+/// Local file name for a remote file name, without any directories or
+/// other surprises. Falls back to the hash.
+fn output_name(name: &str, hash: &Hash) -> String {
+    let name = name.rsplit(['/', '\\']).next().unwrap_or("");
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_control() { '_' } else { c })
+        .collect();
+    let name = name.trim_start_matches('.');
+    if name.is_empty() {
+        return hash.to_string();
+    }
+    name.to_string()
+}
+
+async fn run() -> Result<(), DownloaderError> {
+    let opt = Opt::parse();
 
     stderrlog::new()
         .module(module_path!())
@@ -424,53 +2184,542 @@ async fn main() -> Result<(), DownloaderError> {
         .unwrap();
 
     info!("Connecting…");
-    // TODO: merge clients.
-    let mut txclient = RouterServiceClient::connect(opt.txrouter.clone()).await?;
-    let rxclient = RouterServiceClient::connect(opt.router.clone()).await?;
-    let mut parser = Ax25ParserClient::connect(opt.parser.clone()).await?;
+    let (mut txclient, mut stream) = match &opt.router {
+        Some(router) => {
+            let txrouter = opt.txrouter.as_ref().unwrap_or(router);
+            Transport::router(router.clone(), txrouter.clone()).await?
+        }
+        None => Transport::kiss(&opt.kiss).await?,
+    };
+    let mut parser = Ax25Codec::connect(opt.parser.clone()).await?;
 
     info!("Getting metadata…");
 
-    let mut stream = start_stream(rxclient);
+    let trusted = match &opt.trusted_keys {
+        Some(path) => Some(load_trusted_keys(path).map_err(DownloaderError::TrustedKeys)?),
+        None => None,
+    };
+    let trusted = trusted.as_deref();
 
     if opt.list {
-        list(
+        if let Some(pattern) = opt
+            .roothash
+            .as_deref()
+            .and_then(|arg| arg.strip_prefix(NAME_PREFIX))
+        {
+            let (total, entries) = find(
+                &mut stream,
+                &mut txclient,
+                &mut parser,
+                &opt,
+                pattern,
+                trusted,
+            )
+            .await?;
+            for entry in &entries {
+                println!("{} {} {}", entry.hash, entry.size, entry.name);
+            }
+            if total > entries.len() as u64 {
+                warn!("{} more not shown", total - entries.len() as u64);
+            }
+            return Ok(());
+        }
+        for entry in list(
             &mut stream,
             &mut txclient,
             &mut parser,
             &opt.dst,
             &opt.source,
             opt.timeout,
+            opt.codec(),
+            trusted,
         )
-        .await?;
+        .await?
+        {
+            println!("{} {}", entry.hash, entry.name);
+        }
         return Ok(());
     }
-    let (source_block_size, total_size) = get_meta(
+    if let Some(dir) = &opt.passive {
+        return passive(&mut stream, &mut parser, &opt, dir, trusted).await;
+    }
+    if let Some(dir) = &opt.sync {
+        return sync(&opt, &mut stream, txclient, parser, Path::new(dir), trusted).await;
+    }
+    let hash = resolve(
         &mut stream,
         &mut txclient,
         &mut parser,
+        &opt,
+        opt.roothash.as_deref().unwrap_or_default(),
+        trusted,
+    )
+    .await?;
+    if opt.bundle {
+        return bundle(
+            &opt,
+            &mut stream,
+            txclient,
+            parser,
+            &hash,
+            opt.output.as_deref(),
+            trusted,
+        )
+        .await;
+    }
+    let (data, meta) = fetch(
+        &opt,
+        &mut stream,
+        txclient,
+        parser,
+        &hash,
+        opt.output.as_deref(),
+        trusted,
+    )
+    .await?;
+    let output = match &opt.output {
+        Some(output) => output.clone(),
+        None => output_name(&meta.info.name, &hash),
+    };
+    match meta.range {
+        Some((offset, _)) => write_range(&output, offset, &data),
+        None => write_output(&output, &data, &meta.info),
+    }
+    .map_err(DownloaderError::Output)
+}
+
+/*
+* Get the directory `hash`, and everything in it, into `output` or the
+* directory's name on the uploader.
+*
+* Files already there with the right hash are kept, and files that turn
+* up more than once are only downloaded once. With `--delete`, anything
+* else in the directories is removed.
+*/
+#[allow(clippy::too_many_arguments)]
+async fn bundle(
+    opt: &Opt,
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    txclient: Transport,
+    parser: Ax25Codec,
+    hash: &Hash,
+    output: Option<&str>,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(), DownloaderError> {
+    let (data, meta) = fetch(
+        opt,
+        stream,
+        txclient.clone(),
+        parser.clone(),
+        hash,
+        None,
+        trusted,
+    )
+    .await?;
+    if !meta.info.mime.is_empty() && meta.info.mime != manifest::MIME {
+        warn!("{} is {}, not a directory", hash, meta.info.mime);
+    }
+    let root = PathBuf::from(match output {
+        Some(output) => output.to_string(),
+        None => output_name(&meta.info.name, hash),
+    });
+    fs::create_dir_all(&root).map_err(DownloaderError::Output)?;
+
+    let mut have: HashMap<Hash, PathBuf> = HashMap::new();
+    let mut dirs = vec![(
+        root,
+        Manifest::decode(&data).map_err(DownloaderError::BadManifest)?,
+    )];
+    // Directory modes are set last, in case they don't let us write.
+    let mut modes = Vec::new();
+    while let Some((dir, manifest)) = dirs.pop() {
+        if opt.delete {
+            let wanted = manifest.entries.iter().map(|e| e.name.clone()).collect();
+            remove_stale(&dir, &wanted).map_err(DownloaderError::Output)?;
+        }
+        for entry in manifest.entries {
+            let path = dir.join(&entry.name);
+            let output = path.to_string_lossy().into_owned();
+            match entry.kind {
+                manifest::Kind::Dir => {
+                    info!("Getting directory {}", output);
+                    fs::create_dir_all(&path).map_err(DownloaderError::Output)?;
+                    let (data, _) = fetch(
+                        opt,
+                        stream,
+                        txclient.clone(),
+                        parser.clone(),
+                        &entry.hash,
+                        Some(&output),
+                        trusted,
+                    )
+                    .await?;
+                    let manifest = Manifest::decode(&data).map_err(DownloaderError::BadManifest)?;
+                    modes.push((path.clone(), entry.mode));
+                    dirs.push((path, manifest));
+                }
+                manifest::Kind::File => {
+                    if local_hash(&path, entry.size) == Some(entry.hash) {
+                        info!("Already have {}", output);
+                    } else if let Some(copy) = have.get(&entry.hash) {
+                        info!("Copying {} from {}", output, copy.display());
+                        fs::copy(copy, &path).map_err(DownloaderError::Output)?;
+                    } else {
+                        info!("Getting {} ({} bytes)", output, entry.size);
+                        let (data, meta) = fetch(
+                            opt,
+                            stream,
+                            txclient.clone(),
+                            parser.clone(),
+                            &entry.hash,
+                            Some(&output),
+                            trusted,
+                        )
+                        .await?;
+                        write_output(&output, &data, &meta.info)
+                            .map_err(DownloaderError::Output)?;
+                    }
+                    fs::set_permissions(&path, fs::Permissions::from_mode(entry.mode))
+                        .map_err(DownloaderError::Output)?;
+                    have.insert(entry.hash, path);
+                }
+            }
+        }
+    }
+    for (path, mode) in modes.into_iter().rev() {
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))
+            .map_err(DownloaderError::Output)?;
+    }
+    Ok(())
+}
+
+/// Hash of the local file at `path`, if it's there and of the right size.
+fn local_hash(path: &Path, size: u64) -> Option<Hash> {
+    if fs::metadata(path).ok()?.len() != size {
+        return None;
+    }
+    Some(merkle::hash(&fs::read(path).ok()?))
+}
+
+/// Remove everything in `dir` that isn't in `wanted`. Spool files of
+/// wanted files are kept, so that their downloads can be resumed.
+fn remove_stale(dir: &Path, wanted: &HashSet<String>) -> std::io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if wanted.contains(&name)
+            || name
+                .strip_suffix(".part")
+                .is_some_and(|name| wanted.contains(name))
+        {
+            continue;
+        }
+        info!("Removing {}", entry.path().display());
+        if entry.file_type()?.is_dir() {
+            fs::remove_dir_all(entry.path())?;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/*
+* Keep `dir` a copy of everything in the uploader's listing, every
+* `--interval` seconds or just once.
+*
+* When run periodically, a failed round is logged and tried again next
+* time.
+*/
+async fn sync(
+    opt: &Opt,
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    txclient: Transport,
+    parser: Ax25Codec,
+    dir: &Path,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(), DownloaderError> {
+    loop {
+        match sync_once(opt, stream, txclient.clone(), parser.clone(), dir, trusted).await {
+            Ok(()) => info!("{} is in sync", dir.display()),
+            // Retrying is no use without a connection.
+            Err(DownloaderError::StreamEnded) => return Err(DownloaderError::StreamEnded),
+            Err(e) if opt.interval > 0 => warn!("Sync failed: {}", e),
+            Err(e) => return Err(e),
+        }
+        if opt.interval == 0 {
+            return Ok(());
+        }
+        info!("Next sync in {} seconds", opt.interval);
+        tokio::time::sleep(Duration::from_secs(opt.interval)).await;
+    }
+}
+
+async fn sync_once(
+    opt: &Opt,
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    mut txclient: Transport,
+    mut parser: Ax25Codec,
+    dir: &Path,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(), DownloaderError> {
+    let entries = list(
+        stream,
+        &mut txclient,
+        &mut parser,
         &opt.dst,
         &opt.source,
-        &opt.roothash,
         opt.timeout,
+        opt.codec(),
+        trusted,
     )
     .await?;
-    info!("Source block size: {}", source_block_size);
-    info!("Total size: {}", total_size);
+    fs::create_dir_all(dir).map_err(DownloaderError::Output)?;
+    let mut wanted = HashSet::new();
+    for entry in entries {
+        // The whole directory, which the rest of the listing already
+        // covers.
+        if entry.name == "./" {
+            continue;
+        }
+        let (name, is_dir) = match entry.name.strip_suffix('/') {
+            Some(name) => (name, true),
+            None => (entry.name.as_str(), false),
+        };
+        let name = output_name(name, &entry.hash);
+        let path = dir.join(&name);
+        let output = path.to_string_lossy().into_owned();
+        wanted.insert(name);
+        if is_dir {
+            bundle(
+                opt,
+                stream,
+                txclient.clone(),
+                parser.clone(),
+                &entry.hash,
+                Some(&output),
+                trusted,
+            )
+            .await?;
+            continue;
+        }
+        if fs::read(&path).is_ok_and(|data| merkle::hash(&data) == entry.hash) {
+            debug!("Already have {}", output);
+            continue;
+        }
+        info!("Getting {}", output);
+        let (data, meta) = fetch(
+            opt,
+            stream,
+            txclient.clone(),
+            parser.clone(),
+            &entry.hash,
+            Some(&output),
+            trusted,
+        )
+        .await?;
+        write_output(&output, &data, &meta.info).map_err(DownloaderError::Output)?;
+    }
+    if opt.delete {
+        remove_stale(dir, &wanted).map_err(DownloaderError::Output)?;
+    }
+    Ok(())
+}
 
+/*
+* Get the file `hash`, or the range of it asked for, with its metadata.
+* Anything received is saved to the spool file for `output` as it
+* arrives.
+*/
+#[allow(clippy::too_many_arguments)]
+async fn fetch(
+    opt: &Opt,
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    mut txclient: Transport,
+    mut parser: Ax25Codec,
+    hash: &Hash,
+    output: Option<&str>,
+    trusted: Option<&[VerifyingKey]>,
+) -> Result<(Vec<u8>, Meta), DownloaderError> {
+    let mut harvested = None;
+    if opt.listen > 0.0 && opt.range.is_none() {
+        let (session, early) = harvest(stream, &mut parser, opt, hash, trusted).await?;
+        if let Some(mut session) = session {
+            if session.done() {
+                info!("Got the whole file from other stations' transfers");
+                let data = assemble(hash, &session.meta, &mut session.decoders)?;
+                return Ok((data, session.meta));
+            }
+            harvested = Some((session.meta, early));
+        }
+    }
+    // Don't ask for everything if we already have some of it.
+    let path = spool_path(output, hash);
+    let resuming = std::path::Path::new(&path).exists();
+    let ours = Capabilities::ours(opt.max_packet_size as usize);
+    let basis = match &opt.basis {
+        Some(path) => Some(Basis::read(path)?),
+        None => None,
+    };
+    // Delta requests need to know the uploader supports them.
+    let two_step = opt.two_step || harvested.is_some() || resuming || basis.is_some();
+    // Ranges need the range reply, that only comes with the GET.
+    let (caps, meta, early) = if two_step && opt.range.is_none() {
+        let caps = get_caps(
+            stream,
+            &mut txclient,
+            &mut parser,
+            &opt.dst,
+            &opt.source,
+            &ours,
+            opt.timeout,
+            opt.codec(),
+            trusted,
+        )
+        .await?;
+        (caps, None, None)
+    } else {
+        let mut tries = 0;
+        loop {
+            let (caps, meta, early) = fast_start(
+                stream,
+                &mut txclient,
+                &mut parser,
+                opt,
+                hash,
+                &ours,
+                trusted,
+            )
+            .await?;
+            tries += 1;
+            // Without the range reply we can't tell what we're getting.
+            if meta.is_some() || opt.range.is_none() || tries == MAX_META_RETRIES {
+                break (caps, meta, Some(early));
+            }
+            warn!("No range reply, requesting again");
+        }
+    };
+    if caps.fec & FEC_RAPTOR == 0 {
+        return Err(DownloaderError::Unsupported("raptor codes".to_string()));
+    }
+    if !caps.usable() {
+        return Err(DownloaderError::Unsupported(format!(
+            "packets of at least {MIN_PACKET_SIZE} bytes"
+        )));
+    }
+    let (meta, early) = match &basis {
+        Some(basis) if caps.has(FEATURE_DELTA) => {
+            let (meta, early) = get_delta(
+                stream,
+                &mut txclient,
+                &mut parser,
+                opt,
+                hash,
+                basis,
+                &caps,
+                trusted,
+            )
+            .await?;
+            (Some(meta), Some(early))
+        }
+        Some(_) => {
+            warn!("Uploader doesn't support deltas, getting the whole file");
+            (meta, early)
+        }
+        None => (meta, early),
+    };
+    let meta = match meta {
+        Some(meta) => meta,
+        None if opt.range.is_some() => return Err(DownloaderError::Timeout),
+        None => {
+            let (rcv_hash, meta) = get_meta(
+                stream,
+                &mut txclient,
+                &mut parser,
+                &opt.dst,
+                &opt.source,
+                &opt.prefix(hash),
+                opt.timeout,
+                opt.codec(),
+                trusted,
+            )
+            .await?;
+            // Some other file with the same hash prefix.
+            if rcv_hash != *hash {
+                return Err(DownloaderError::Rejected(ErrorReason::NotFound));
+            }
+            meta
+        }
+    };
+    // What we overheard is only any use if we'd get the same encoding.
+    let early = match harvested {
+        Some((heard, early))
+            if heard.layout == meta.layout && heard.compression == meta.compression =>
+        {
+            Some(early)
+        }
+        Some(_) => {
+            warn!("Overheard data is encoded differently than for us, discarding it");
+            None
+        }
+        None => early,
+    };
+    let info = &meta.info;
+    info!("Source blocks: {}", meta.layout.blocks());
+    info!("Total size: {}", meta.original_size);
+    if meta.compression != Compression::None {
+        info!(
+            "Compressed with {} to {}",
+            meta.compression, meta.layout.size
+        );
+    }
+    if !info.name.is_empty() {
+        info!("Name: {:?} ({})", info.name, info.mime);
+    }
+    if !info.description.is_empty() {
+        info!("Description: {}", info.description);
+    }
+    if let Some((offset, range_hash)) = &meta.range {
+        info!("Range at {}, hash {}", offset, range_hash);
+    }
+    if let Some(d) = &meta.delta {
+        info!("Delta of {} bytes, hash {}", d.file_size, d.hash);
+    }
     info!("Getting data…");
     let source_block = download_block(
-        &opt,
-        &mut stream,
-        txclient,
-        parser,
-        &opt.roothash,
-        total_size,
-        source_block_size,
+        opt, stream, txclient, parser, hash, &meta, &caps, trusted, early, &path,
     )
     .await?;
 
     info!("Downloaded size {:?}", source_block.len());
-    fs::write(opt.output, source_block).expect("write block");
-    Ok(())
+    let source_block = match (&meta.delta, &basis) {
+        (Some(d), Some(basis)) => {
+            let data = delta::apply(
+                &basis.data,
+                basis.block_size,
+                &source_block,
+                d.file_size as usize,
+            )
+            .map_err(DownloaderError::BadDelta)?;
+            let digest = merkle::hash(&data);
+            if digest != *hash {
+                return Err(DownloaderError::ChecksumMismatch(
+                    digest.to_string(),
+                    hash.to_string(),
+                ));
+            }
+            info!("Made {} bytes from the delta", data.len());
+            data
+        }
+        _ => source_block,
+    };
+    Ok((source_block, meta))
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("Error: {e}");
+        std::process::exit(1);
+    }
 }
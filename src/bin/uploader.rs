@@ -1,32 +1,52 @@
 use async_std::task;
-use ax25::ax25_parser_client::Ax25ParserClient;
-use ax25::packet::Ui;
-use ax25::Packet;
-use ax25::SerializeRequest;
-use ax25ms::router_service_client::RouterServiceClient;
-use ax25ms::Frame;
-use ax25ms::SendRequest;
 use clap::Parser;
-use lazy_static::lazy_static;
+use ed25519_dalek::SigningKey;
 use log::{debug, info, warn};
 use rand::prelude::SliceRandom;
-use rand::thread_rng;
-use regex::Regex;
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
 use std::fs;
-use std::time::Duration;
-use tokio_stream::StreamExt;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
 
+use lib::ax25codec::Ax25Codec;
+use lib::compression::Compression;
+use lib::delta::{self, Signature};
+use lib::kiss::KissOpt;
+use lib::layout::BlockLayout;
+use lib::manifest::{self, Manifest};
+use lib::merkle;
+use lib::protocol::{
+    Capabilities, Codec, ErrorReason, FileInfo, FindEntry, Hash, HashPrefix, ListEntry, Message,
+    ProtocolError,
+};
+use lib::sack::EsiSet;
+use lib::signing::{load_or_create_key, public_key_hex, sign};
+use lib::transport::Transport;
 use lib::{ax25, ax25ms, make_packet};
 
 #[derive(clap::Parser, Debug)]
 #[command(version, about)]
 struct Opt {
-    #[clap(short, long = "router")]
-    router: String,
+    /// ax25ms router to send and receive frames with.
+    #[clap(
+        short,
+        long = "router",
+        required_unless_present_any = ["kiss_tcp", "kiss_serial"],
+        conflicts_with_all = ["kiss_tcp", "kiss_serial"]
+    )]
+    router: Option<String>,
 
+    #[command(flatten)]
+    kiss: KissOpt,
+
+    /// ax25ms parser service for AX.25 frames. Without it, they're
+    /// encoded and decoded natively.
     #[clap(short, long = "parser")]
-    parser: String,
+    parser: Option<String>,
 
     #[clap(short, long = "input")]
     input: String,
@@ -37,143 +57,157 @@ struct Opt {
     #[clap(short = 'R', long = "repair", default_value = "50")]
     repair: usize,
 
+    /// Max number of source symbols per source block.
+    #[clap(long = "block-symbols", default_value = "256")]
+    block_symbols: usize,
+
     #[clap(short = 'S', long = "source")]
     source: String,
 
     #[clap(long = "repeat", default_value = "1")]
     repeat: usize,
-}
 
-fn float_to_usize(f: f64) -> Option<usize> {
-    let ret = f as usize;
-    let back = ret as f64;
-    if back != f {
-        return None;
-    }
-    Some(ret)
+    /// Send the text form of the protocol, for debugging.
+    #[clap(long = "text")]
+    text: bool,
+
+    /// Never compress files, even for stations that support it.
+    #[clap(long = "no-compression")]
+    no_compression: bool,
+
+    /// Data frames between header frames, that let receivers decode
+    /// without the metadata reply. 0 to not send header frames.
+    #[clap(long = "header-interval", default_value = "32")]
+    header_interval: usize,
+
+    /// Broadcast this file, instead of serving requests. Can be given
+    /// more than once.
+    #[clap(long = "broadcast")]
+    broadcast: Vec<String>,
+
+    /// Destination address for broadcasts.
+    #[clap(long = "broadcast-dst", default_value = "CQ")]
+    broadcast_dst: String,
+
+    /// Seconds to wait between broadcast rounds.
+    #[clap(long = "broadcast-pause", default_value = "10")]
+    broadcast_pause: f32,
+
+    /// Broadcast rounds before exiting. 0 means forever.
+    #[clap(long = "broadcast-rounds", default_value = "0")]
+    broadcast_rounds: usize,
+
+    /// File with the Ed25519 key to sign replies with. Created if it
+    /// doesn't exist.
+    #[clap(long = "key")]
+    key: Option<String>,
 }
 
-fn usize_to_float(f: usize) -> Option<f64> {
-    let ret = f as f64;
-    let back = ret as usize;
-    if back != f {
-        return None;
+impl Opt {
+    fn codec(&self) -> Codec {
+        if self.text {
+            Codec::Text
+        } else {
+            Codec::Binary
+        }
     }
-    Some(ret)
 }
 
-use std::str;
 async fn get_request(
-    stream: &mut tonic::Streaming<Frame>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
-) -> Result<(String, String), Box<dyn std::error::Error>> {
+    stream: &mut mpsc::Receiver<ax25ms::Frame>,
+    parser: &mut Ax25Codec,
+) -> Result<(String, Vec<u8>), Box<dyn std::error::Error>> {
     loop {
-        let frame = stream.next().await.unwrap().unwrap().payload;
-        let parsed = parser
-            .parse(tonic::Request::new(ax25::ParseRequest {
-                payload: frame,
-                check_fcs: true,
-            }))
-            .await?
-            .into_inner()
-            .packet
-            .expect("surely the RPC reply has a packet");
+        let frame = stream.recv().await.ok_or("frame stream ended")?.payload;
+        let Some(parsed) = parser.parse(frame).await? else {
+            continue;
+        };
         let ui = match parsed.frame_type {
             Some(ax25::packet::FrameType::Ui(ui)) => ui,
             _ => continue,
         };
 
-        let cmd = match str::from_utf8(&ui.payload) {
-            Ok(x) => x,
-            _ => {
-                //warn!("Invalid request: {:?}", ui.payload);
-                continue;
-            }
-        };
-        return Ok((parsed.src, cmd.to_string()));
+        return Ok((parsed.src, ui.payload));
     }
 }
 
-fn max_source_syms(size: usize, packet_size: usize) -> usize {
-    let f = (usize_to_float(size).unwrap() / usize_to_float(packet_size).unwrap()).ceil();
-    float_to_usize(f).unwrap()
+/// Start receiving requests from `stream`, returning the source and
+/// payload of each UI frame.
+fn start_requests(
+    mut stream: mpsc::Receiver<ax25ms::Frame>,
+    mut parser: Ax25Codec,
+) -> mpsc::Receiver<(String, Vec<u8>)> {
+    let (tx, rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        loop {
+            let req = match get_request(&mut stream, &mut parser).await {
+                Ok(req) => req,
+                Err(e) => {
+                    warn!("Failed to receive request: {}", e);
+                    return;
+                }
+            };
+            if tx.send(req).await.is_err() {
+                return;
+            }
+        }
+    });
+    rx
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn transmit(
-    client: &mut RouterServiceClient<tonic::transport::Channel>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
+    client: &mut Transport,
+    parser: &mut Ax25Codec,
     dst: &str,
     src: String,
     tag: u16,
-    packet_size: usize,
-    nb_repair: u32,
-    source_data: Vec<u8>,
-    packets: usize,
+    layout: &BlockLayout,
+    source_data: &[u8],
+    txlist: Vec<(u16, u16)>,
+    headers: Option<&Headers>,
+    codec: Codec,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let len = source_data.len();
-
-    let max_source_symbols = max_source_syms(source_data.len(), packet_size);
-
-    debug!("Max source symbols: {}", max_source_symbols);
-    debug!("Total len: {}", len);
+    debug!("Source blocks: {}", layout.blocks());
+    debug!("Total len: {}", layout.size);
     // State that needs to be sent:
-    // * max_source_symbols
+    // * block_symbols
     // * total size
-    // * packet_size is implicitly sent by seeing the block size.
+    // * packet_size
+    // All of which are sent in the metadata reply, and in header frames.
 
-    let mut encoder = raptor_code::SourceBlockEncoder::new(&source_data, max_source_symbols)?;
-    let n = (encoder.nb_source_symbols() + nb_repair) as u16;
+    let mut encoders = HashMap::new();
 
     // Transmit RPC.
 
-    debug!("Total chunks: {}", n);
-    let txlist = {
-        let mut x: Vec<u16> = (0..n).step_by(1).collect();
-        x.shuffle(&mut thread_rng());
-        x.resize(packets, 0);
-        x
-    };
-
-    for esi in txlist {
+    debug!("Total chunks: {}", txlist.len());
+    for (i, (block, esi)) in txlist.into_iter().enumerate() {
+        if let Some(headers) = headers {
+            if i % headers.interval == 0 {
+                let request = make_packet(parser, dst, &src, headers.payload.clone()).await?;
+                client.send(request).await?;
+            }
+        }
+        let encoder = match encoders.entry(block) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let n = block as usize;
+                e.insert(raptor_code::SourceBlockEncoder::new(
+                    &layout.padded_block(source_data, n),
+                    layout.source_symbols(n),
+                )?)
+            }
+        };
         let encoding_symbol = encoder.fountain(esi as u32);
-        let len = encoding_symbol.len();
-
-        let mut payload = tag.to_be_bytes().to_vec();
-        payload.extend(esi.to_be_bytes().to_vec());
-        payload.extend(encoding_symbol);
-        // TODO: surely we can default these values?
-        let request = tonic::Request::new(SerializeRequest {
-            set_fcs: true,
-            packet: Some(Packet {
-                dst: dst.to_string(),
-                src: src.clone(),
-                fcs: 0,
-                aprs: None,
-                repeater: vec![],
-                command_response: false,
-                command_response_la: true,
-                rr_dst1: false,
-                rr_extseq: false,
-                frame_type: Some(ax25::packet::FrameType::Ui(Ui {
-                    pid: 0xF0_i32,
-                    push: 0,
-                    payload,
-                })),
-            }),
-        });
-        let serd = parser.serialize(request).await?;
 
-        let request = SendRequest {
-            frame: Some(Frame {
-                payload: serd.into_inner().payload,
-            }),
+        let msg = Message::Data {
+            tag,
+            block,
+            esi,
+            symbol: encoding_symbol,
         };
-        client.send(tonic::Request::new(request.clone())).await?;
-        //println!("Sent esi {} of size {}", esi, &len);
-        if false {
-            let millis: u64 = 8000 * len as u64 / 9600;
-            task::sleep(Duration::from_millis(millis)).await;
-        }
+        let request = make_packet(parser, dst, &src, msg.encode(codec)).await?;
+        client.send(request).await?;
     }
     Ok(())
 }
@@ -182,181 +216,302 @@ async fn transmit(
 enum Request {
     Meta {
         dst: String,
+        tag: u16,
         hash: String,
     },
     Get {
         dst: String,
         #[allow(dead_code)]
-        frequency: String,
+        frequency: u64,
         tag: u16,
-        #[allow(dead_code)]
-        existing: u32,
+        existing: u64,
         id: String,
+        range: Option<(u64, u64)>,
+        delta: Option<(u64, u64)>,
+    },
+    Basis {
+        dst: String,
+        tag: u16,
+        id: String,
+        block_size: u64,
+        first: u64,
+        sigs: Vec<Signature>,
+    },
+    Tree {
+        dst: String,
+        tag: u16,
+        id: String,
+        first: u64,
     },
     List {
         dst: String,
         tag: u16,
+        pages: Vec<u16>,
+    },
+    Find {
+        dst: String,
+        tag: u16,
+        pattern: String,
+    },
+    Sack {
+        dst: String,
+        tag: u16,
+        id: String,
+        blocks: Vec<(u16, EsiSet)>,
+    },
+    Caps {
+        dst: String,
+        tag: u16,
+        caps: Capabilities,
     },
-}
 
-lazy_static! {
-    //                                      cmd    tag   freq    exist hash
-    static ref GET_RE: Regex = Regex::new(r"(G|GM) (\d+) ([^ ]+) (\d+) (\w+)").unwrap();
-    static ref META_RE: Regex = Regex::new(r"M (\w+)").unwrap();
-    static ref LIST_RE: Regex = Regex::new(r"L (\d+)").unwrap();
-}
+    /// A request by a hash prefix of more than one file.
+    Ambiguous {
+        dst: String,
+        tag: u16,
+        candidates: Vec<Hash>,
+    },
 
-fn parse_get_request(
-    cmd: &str,
-    dst: &str,
-    frequency: &str,
-    tag: &str,
-    existing: &str,
-    hash: &str,
-) -> Result<Vec<Request>, Box<dyn std::error::Error>> {
-    let tag = match tag.parse::<u16>() {
-        Ok(x) => x,
-        _ => {
-            warn!("Tag is not u16");
-            return Ok(vec![]);
-        }
-    };
-    let existing = match existing.parse::<u32>() {
-        Ok(x) => x,
-        _ => {
-            warn!("`existing` is not u32");
-            return Ok(vec![]);
-        }
-    };
-    let g = Request::Get {
-        dst: dst.to_string(),
-        frequency: frequency.to_string(),
-        tag,
-        existing,
-        id: hash.to_string(),
-    };
-    if cmd == "G" {
-        return Ok(vec![g]);
-    }
-    if cmd == "GM" {
-        let m = Request::Meta {
-            dst: dst.to_string(),
-            hash: hash.to_string(),
-        };
-        return Ok(vec![m, g]);
-    }
-    Ok(vec![])
+    /// A request to reply to with an error.
+    Reject {
+        dst: String,
+        tag: u16,
+        reason: ErrorReason,
+    },
 }
 
-fn parse_request(src: &str, s: &str) -> Result<Vec<Request>, Box<dyn std::error::Error>> {
-    if let Some(m) = GET_RE.captures(s) {
-        info!("Got request from {} {:?}", &src, s);
-        return parse_get_request(
-            &m[1], /* cmd */
-            src, &m[3], /* frequency */
-            &m[2], /* tag */
-            &m[4], /* existing */
-            &m[5], /* hash */
-        );
-    }
-    if let Some(m) = META_RE.captures(s) {
-        return Ok(vec![Request::Meta {
-            dst: src.to_string(),
-            hash: m[1].to_string(),
-        }]);
-    }
-    if let Some(m) = LIST_RE.captures(s) {
-        let tag = match m[1].parse::<u16>() {
-            Ok(x) => x,
-            _ => {
-                warn!("Tag is not u16");
-                return Ok(vec![]);
+fn parse_request(
+    index: &DirectoryIndex,
+    src: &str,
+    payload: &[u8],
+) -> Result<Vec<Request>, ProtocolError> {
+    let dst = src.to_string();
+    let msg = Message::decode(payload)?;
+    Ok(match msg {
+        Message::Get {
+            tag,
+            frequency,
+            existing,
+            ref hash,
+            meta,
+            range,
+            delta,
+        } => {
+            info!("Got request from {} {:?}", &src, msg);
+            let id = match index.resolve(hash) {
+                Ok(id) => id,
+                Err(candidates) => {
+                    return Ok(vec![Request::Ambiguous {
+                        dst,
+                        tag,
+                        candidates,
+                    }])
+                }
+            };
+            let g = Request::Get {
+                dst: dst.clone(),
+                frequency,
+                tag,
+                existing,
+                id: id.clone(),
+                range,
+                delta,
+            };
+            // Range requests always get a range reply instead.
+            if !meta || range.is_some() {
+                return Ok(vec![g]);
             }
-        };
-        return Ok(vec![Request::List {
-            dst: src.to_string(),
+            let m = Request::Meta { dst, tag, hash: id };
+            vec![m, g]
+        }
+        Message::Sack { tag, hash, blocks } => {
+            info!("Got SACK from {} for {} blocks", &src, blocks.len());
+            vec![Request::Sack {
+                dst,
+                tag,
+                id: hash.to_string(),
+                blocks,
+            }]
+        }
+        Message::Basis {
             tag,
-        }]);
-    }
-    Ok(vec![])
+            hash,
+            block_size,
+            first,
+            sigs,
+        } => {
+            debug!(
+                "Got {} signatures from block {} from {}",
+                sigs.len(),
+                first,
+                &src
+            );
+            vec![Request::Basis {
+                dst,
+                tag,
+                id: hash.to_string(),
+                block_size,
+                first,
+                sigs,
+            }]
+        }
+        Message::Meta { tag, hash } => match index.resolve(&hash) {
+            Ok(id) => vec![Request::Meta { dst, tag, hash: id }],
+            Err(candidates) => vec![Request::Ambiguous {
+                dst,
+                tag,
+                candidates,
+            }],
+        },
+        Message::Tree { tag, hash, first } => vec![Request::Tree {
+            dst,
+            tag,
+            id: hash.to_string(),
+            first,
+        }],
+        Message::List { tag, pages } => vec![Request::List { dst, tag, pages }],
+        Message::Find { tag, pattern } => {
+            info!("Got find request from {} for {:?}", &src, pattern);
+            vec![Request::Find { dst, tag, pattern }]
+        }
+        Message::Caps { tag, caps } => {
+            info!("Got capabilities from {}: {:?}", &src, caps);
+            if !caps.usable() {
+                return Ok(vec![Request::Reject {
+                    dst,
+                    tag,
+                    reason: ErrorReason::BadCaps,
+                }]);
+            }
+            vec![Request::Caps { dst, tag, caps }]
+        }
+
+        // Replies and data, to us or other stations.
+        Message::MetaReply { .. }
+        | Message::ListReply { .. }
+        | Message::FindReply { .. }
+        | Message::Data { .. }
+        | Message::CapsReply { .. }
+        | Message::Error { .. }
+        | Message::Header { .. }
+        | Message::Join { .. }
+        | Message::RangeReply { .. }
+        | Message::DeltaReply { .. }
+        | Message::TreeReply { .. }
+        | Message::Signed { .. } => vec![],
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn handle_meta(
-    client: &mut RouterServiceClient<tonic::transport::Channel>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
-    block: &[u8],
+    client: &mut Transport,
+    parser: &mut Ax25Codec,
     dst: &str,
     src: String,
-    hash: String,
-    packet_size: usize,
+    tag: u16,
+    hash: &str,
+    transfer: &Transfer,
+    info: FileInfo,
     repeat: usize,
+    enc: &ReplyEncoder,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let size = block.len();
-    let max_source_symbols = max_source_syms(size, packet_size);
-    let reply = make_packet(
-        parser,
-        dst,
-        &src,
-        format!("m {} {} {}", hash, max_source_symbols, size),
-    )
-    .await?;
-    let req = ax25ms::SendRequest {
-        frame: Some(ax25ms::Frame { payload: reply }),
+    let msg = Message::MetaReply {
+        tag,
+        hash: Hash::from_hex(hash).expect("index has valid hashes"),
+        layout: transfer.layout,
+        compression: transfer.compression,
+        original_size: transfer.original_size,
+        info,
     };
+    let reply = make_packet(parser, dst, &src, enc.encode(msg)).await?;
     for _ in 0..repeat {
-        client.send(tonic::Request::new(req.clone())).await?;
+        client.send(reply.clone()).await?;
     }
     Ok(())
 }
 
-async fn handle_get(
-    client: &mut RouterServiceClient<tonic::transport::Channel>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
-    block: &[u8],
-    dst: &str,
-    src: String,
-    tag: u16,
-    packet_size: usize,
-    nb_repair: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    debug!("Handling GET");
-
-    // Pad to nearest payload size.
-    let max_source_symbols = max_source_syms(block.len(), packet_size);
+/// Number of packets to send for each source block.
+///
+/// A fresh request gets a generous fixed overhead. A retry (`existing` > 0)
+/// only gets enough to cover what the downloader says it's missing, spread
+/// over the blocks since we don't know which ones are incomplete.
+fn packet_budget(layout: &BlockLayout, existing: usize) -> Vec<usize> {
+    let syms: Vec<usize> = (0..layout.blocks())
+        .map(|n| layout.source_symbols(n))
+        .collect();
+    if existing == 0 {
+        return syms
+            .iter()
+            .map(|s| 3 * (*s as f32 * 1.2 + 2.0) as usize) // TODO: tweak default overhead.
+            .collect();
+    }
+    let missing = layout
+        .size
+        .saturating_sub(existing)
+        .div_ceil(layout.packet_size);
+    let total = (missing as f32 * 1.2 + 2.0).ceil() as usize;
+    let total_syms: usize = syms.iter().sum();
+    syms.iter()
+        .map(|s| std::cmp::max(1, (total * s).div_ceil(total_syms)))
+        .collect()
+}
 
-    let source_data = {
-        let mut x = block.to_vec();
+/// Encoding symbols to send for a GET.
+fn get_txlist(layout: &BlockLayout, nb_repair: usize, existing: usize) -> Vec<(u16, u16)> {
+    debug!("Handling GET, downloader has {} bytes", existing);
 
-        let excess = x.len() % max_source_symbols;
-        let padded_size = if excess == 0 {
-            x.len()
+    let mut rng = rand::rng();
+    let mut txlist = Vec::new();
+    for (n, packets) in packet_budget(layout, existing).into_iter().enumerate() {
+        let syms = layout.source_symbols(n);
+        let esis: Vec<u16> = if existing == 0 {
+            // Pick unique ESIs. Any ESI is as good as any other, so if the
+            // budget is bigger than source+repair, just use more repair symbols.
+            let mut esis: Vec<u16> = (0..std::cmp::max(syms + nb_repair, packets))
+                .map(|esi| u16::try_from(esi).unwrap_or(u16::MAX))
+                .collect();
+            esis.dedup();
+            esis.shuffle(&mut rng);
+            esis.truncate(packets);
+            esis
         } else {
-            x.len() + max_source_symbols - excess
+            // Retry. Random ESIs are most likely ones the downloader
+            // doesn't already have.
+            rand::seq::index::sample(&mut rng, 1 << 16, std::cmp::min(packets, 1 << 16))
+                .into_iter()
+                .map(|esi| esi as u16)
+                .collect()
         };
-        x.resize(padded_size, 0); // multiple of packet_size.
-        x
-    };
-    let packets = 3 * ((source_data.len() / packet_size) as f32 * 1.2 + 2.0) as usize; // TODO: tweak default overhead.
-    debug!("Sending {} packets", packets);
+        txlist.extend(esis.into_iter().map(|esi| (n as u16, esi)));
+    }
+    debug!("Sending {} packets", txlist.len());
+    txlist
+}
 
-    transmit(
-        client,
-        parser,
-        dst,
-        src,
-        tag,
-        packet_size,
-        nb_repair,
-        source_data,
-        packets,
-    )
-    .await
+/// Encoding symbols to send for what a SACK says is still missing.
+///
+/// Blocks not mentioned in the SACK are already decoded by the downloader.
+fn sack_txlist(layout: &BlockLayout, blocks: &[(u16, EsiSet)]) -> Vec<(u16, u16)> {
+    let mut txlist = Vec::new();
+    for (n, esis) in blocks {
+        if *n as usize >= layout.blocks() {
+            warn!("SACK for nonexisting block {}", n);
+            continue;
+        }
+        let needed = layout
+            .source_symbols(*n as usize)
+            .saturating_sub(esis.len());
+        let packets = (needed as f32 * 1.2 + 2.0) as usize;
+        txlist.extend(esis.missing(packets).into_iter().map(|esi| (*n, esi)));
+    }
+    debug!("Sending {} packets for SACK", txlist.len());
+    txlist
 }
 
 #[derive(Debug)]
 pub enum UploaderError {
     RPCError(tonic::transport::Error),
-    RPCStatusError(tonic::Status),
+    RPCStatusError(Box<tonic::Status>),
     IOError(std::io::Error),
     StreamError(Box<dyn std::error::Error>),
     ChecksumMismatch(String, String),
@@ -375,7 +530,7 @@ impl From<tonic::transport::Error> for UploaderError {
 }
 impl From<tonic::Status> for UploaderError {
     fn from(error: tonic::Status) -> Self {
-        UploaderError::RPCStatusError(error)
+        UploaderError::RPCStatusError(Box::new(error))
     }
 }
 impl From<std::io::Error> for UploaderError {
@@ -386,64 +541,338 @@ impl From<std::io::Error> for UploaderError {
 
 struct File {
     name: String,
+    mtime: u64,
+    mime: String,
+    description: String,
+    data: Source,
+}
+
+/// Where a file's data comes from.
+enum Source {
+    Path(PathBuf),
+
+    /// Manifest of a directory.
+    Manifest(Vec<u8>),
+}
+
+/// Files with this suffix hold a description of the file they're named
+/// after, and aren't served themselves.
+const DESCRIPTION_SUFFIX: &str = ".description";
+
+/// Max length of a description, to keep metadata replies small.
+const MAX_DESCRIPTION: usize = 80;
+
+/// MIME type from the file name extension.
+fn guess_mime(name: &str) -> &'static str {
+    let ext = match name.rsplit_once('.') {
+        Some((_, ext)) => ext.to_ascii_lowercase(),
+        None => return "application/octet-stream",
+    };
+    match ext.as_str() {
+        "txt" | "md" => "text/plain",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "wav" => "audio/wav",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        _ => "application/octet-stream",
+    }
+}
+
+/// First line of the description file for `path`, if any.
+fn read_description(path: &Path) -> String {
+    let mut desc = path.as_os_str().to_owned();
+    desc.push(DESCRIPTION_SUFFIX);
+    match fs::read_to_string(desc) {
+        Ok(s) => s
+            .lines()
+            .next()
+            .unwrap_or("")
+            .chars()
+            .take(MAX_DESCRIPTION)
+            .collect(),
+        Err(_) => String::new(),
+    }
 }
 
 /// Block index created from a directory of files.
 ///
-/// No recursive scanning, just files directly in the directory.
+/// Subdirectories, and the directory itself, are served as manifests of
+/// what's in them.
 pub struct DirectoryIndex {
     files: HashMap<String, File>,
-    base: String,
+
+    /// What's directly in the directory, for the listing.
+    top: Vec<FileEntry>,
+
+    /// Everything in the directory tree, by path, for find requests.
+    paths: Vec<FindEntry>,
 }
 
 impl DirectoryIndex {
     pub fn new(dir: &str) -> Result<DirectoryIndex, UploaderError> {
-        let mut files = HashMap::new();
+        let mut index = DirectoryIndex {
+            files: HashMap::new(),
+            top: Vec::new(),
+            paths: Vec::new(),
+        };
+        let manifest = index.index_dir(Path::new(dir), "")?;
+        for e in &manifest.entries {
+            index.top.push(FileEntry {
+                name: match e.kind {
+                    manifest::Kind::File => e.name.clone(),
+                    manifest::Kind::Dir => format!("{}/", e.name),
+                },
+                hash: e.hash.to_string(),
+            });
+        }
+        let data = manifest.encode();
+        let hash = merkle::hash(&data).to_string();
+        let name = Path::new(dir)
+            .canonicalize()?
+            .file_name()
+            .map_or(".".to_string(), |n| n.to_string_lossy().into_owned());
+        info!("Indexed directory: {} {}", hash, name);
+        index.files.insert(
+            hash.clone(),
+            File {
+                name,
+                mtime: mtime(&fs::metadata(dir)?)?,
+                mime: manifest::MIME.to_string(),
+                description: String::new(),
+                data: Source::Manifest(data),
+            },
+        );
+        index.top.push(FileEntry {
+            name: "./".to_string(),
+            hash,
+        });
+        Ok(index)
+    }
+
+    /// Index everything in `dir`, at `prefix` from the top, returning its
+    /// manifest.
+    fn index_dir(&mut self, dir: &Path, prefix: &str) -> Result<Manifest, UploaderError> {
+        let mut manifest = Manifest::default();
         for entry in fs::read_dir(dir)? {
             let entry = entry?;
             let path = entry.path();
             let metadata = fs::metadata(&path)?;
-            //let fn = Path::new("./foo.file");
-            let hash = sha256::try_digest(path.as_path()).unwrap();
-            let fname = path.file_name().unwrap().to_str().unwrap();
+            let fname = match path.file_name().and_then(|n| n.to_str()) {
+                Some(fname) => fname.to_string(),
+                None => {
+                    warn!("Skipping file with non-UTF-8 name {:?}", path);
+                    continue;
+                }
+            };
+            if fname.ends_with(DESCRIPTION_SUFFIX) {
+                continue;
+            }
+            let mode = metadata.permissions().mode() & 0o777;
             if metadata.is_file() {
-                files.insert(
-                    hash.clone(),
+                let hash = merkle::hash(&fs::read(&path)?);
+                self.files.insert(
+                    hash.to_string(),
+                    File {
+                        name: fname.clone(),
+                        mtime: mtime(&metadata)?,
+                        mime: guess_mime(&fname).to_string(),
+                        description: read_description(&path),
+                        data: Source::Path(path.clone()),
+                    },
+                );
+                info!("Indexed file: {} {}", hash, path.display());
+                self.paths.push(FindEntry {
+                    hash,
+                    size: metadata.len(),
+                    name: format!("{prefix}{fname}"),
+                });
+                manifest.entries.push(manifest::Entry {
+                    name: fname,
+                    kind: manifest::Kind::File,
+                    mode,
+                    size: metadata.len(),
+                    hash,
+                });
+            } else if metadata.is_dir() {
+                // Symlinked directories could make loops.
+                if fs::symlink_metadata(&path)?.file_type().is_symlink() {
+                    warn!("Skipping symlinked directory {}", path.display());
+                    continue;
+                }
+                let data = self
+                    .index_dir(&path, &format!("{prefix}{fname}/"))?
+                    .encode();
+                let hash = merkle::hash(&data);
+                info!("Indexed directory: {} {}", hash, path.display());
+                self.paths.push(FindEntry {
+                    hash,
+                    size: data.len() as u64,
+                    name: format!("{prefix}{fname}/"),
+                });
+                manifest.entries.push(manifest::Entry {
+                    name: fname.clone(),
+                    kind: manifest::Kind::Dir,
+                    mode,
+                    size: data.len() as u64,
+                    hash,
+                });
+                self.files.insert(
+                    hash.to_string(),
                     File {
-                        name: fname.to_string(),
+                        name: fname,
+                        mtime: mtime(&metadata)?,
+                        mime: manifest::MIME.to_string(),
+                        description: read_description(&path),
+                        data: Source::Manifest(data),
                     },
                 );
             }
-            info!("Indexed file: {} {}", hash, fname);
         }
-        Ok(DirectoryIndex {
-            base: dir.to_string(),
-            files,
-        })
+        Ok(manifest)
     }
 
     pub fn get_block(&self, hash: &str) -> Result<Vec<u8>, UploaderError> {
         match self.files.get(hash) {
-            Some(f) => {
-                info!("Found hash {} at {}", hash, f.name);
-                Ok(fs::read(std::path::Path::new(&self.base).join(&f.name))?)
+            Some(File {
+                data: Source::Path(path),
+                ..
+            }) => {
+                info!("Found hash {} at {}", hash, path.display());
+                Ok(fs::read(path)?)
+            }
+            Some(File {
+                data: Source::Manifest(data),
+                name,
+                ..
+            }) => {
+                info!("Found hash {} as the manifest of {}", hash, name);
+                Ok(data.clone())
             }
             None => Err(UploaderError::HashNotFound),
         }
     }
 
-    pub fn list(&self) -> Vec<FileEntry> {
-        let mut ret = Vec::new();
-        for (hash, f) in self.files.iter() {
-            ret.push(FileEntry {
-                name: f.name.clone(),
-                hash: hash.to_owned(),
-            });
+    pub fn info(&self, hash: &str) -> Option<FileInfo> {
+        self.files.get(hash).map(|f| FileInfo {
+            name: f.name.clone(),
+            mtime: f.mtime,
+            mime: f.mime.clone(),
+            description: f.description.clone(),
+        })
+    }
+
+    /// The file a hash prefix is of.
+    ///
+    /// A whole hash, or a prefix of no file, is returned as is, for the
+    /// lookup to not find. A prefix of more than one file is an error,
+    /// with what it could be.
+    pub fn resolve(&self, prefix: &HashPrefix) -> Result<String, Vec<Hash>> {
+        if let Some(hash) = prefix.full() {
+            return Ok(hash.to_string());
         }
+        let hex = prefix.to_string();
+        let mut found: Vec<&String> = self.files.keys().filter(|h| h.starts_with(&hex)).collect();
+        match found.len() {
+            0 => Ok(hex),
+            1 => Ok(found[0].clone()),
+            _ => {
+                found.sort();
+                Err(found
+                    .into_iter()
+                    .map(|h| Hash::from_hex(h).expect("index has valid hashes"))
+                    .collect())
+            }
+        }
+    }
+
+    /// What's directly in the directory, with subdirectories ending in
+    /// `/`, and the directory itself as `./`.
+    pub fn list(&self) -> Vec<FileEntry> {
+        let mut ret = self.top.clone();
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
+        ret
+    }
+
+    /// Files and directories matching the glob `pattern`, sorted by path.
+    ///
+    /// A pattern with a `/` is matched against the whole path, and one
+    /// without against just the name, wherever it is. A leading `/` is
+    /// the top of the directory.
+    pub fn find(&self, pattern: &str) -> Vec<FindEntry> {
+        let pattern = pattern.trim_end_matches('/');
+        let (pattern, whole_path) = match pattern.strip_prefix('/') {
+            Some(pattern) => (pattern, true),
+            None => (pattern, pattern.contains('/')),
+        };
+        let mut ret: Vec<FindEntry> = self
+            .paths
+            .iter()
+            .filter(|e| {
+                let path = e.name.trim_end_matches('/');
+                let name = if whole_path {
+                    path
+                } else {
+                    path.rsplit('/').next().unwrap_or(path)
+                };
+                glob_match(pattern, name)
+            })
+            .cloned()
+            .collect();
+        ret.sort_by(|a, b| a.name.cmp(&b.name));
         ret
     }
 }
 
+/// Whether `name` matches the glob `pattern`, where `*` is any number of
+/// characters and `?` is one, except `/`.
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    let (mut p, mut n) = (0, 0);
+    // Where to go back to if what follows the last `*` doesn't match.
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if (c == '?' && name[n] != '/') || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) if name[sn] != '/' => {
+                    star = Some((sp, sn + 1));
+                    p = sp + 1;
+                    n = sn + 1;
+                }
+                _ => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Modification time, in seconds since the Unix epoch.
+fn mtime(metadata: &fs::Metadata) -> std::io::Result<u64> {
+    Ok(metadata
+        .modified()?
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs()))
+}
+
+#[derive(Clone)]
 pub struct FileEntry {
     name: String,
     hash: String,
@@ -463,82 +892,973 @@ fn get_block(id: &str) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
     Ok(data)
 }
 
+/// Encodes replies, signed if we have a key.
+struct ReplyEncoder {
+    codec: Codec,
+    key: Option<SigningKey>,
+}
+
+impl ReplyEncoder {
+    fn encode(&self, msg: Message) -> Vec<u8> {
+        match &self.key {
+            Some(key) => sign(msg, key).encode(self.codec),
+            None => msg.encode(self.codec),
+        }
+    }
+}
+
+/// Capabilities in common with each station that has sent theirs.
+type Peers = HashMap<String, Capabilities>;
+
+/// Packet size to use for `dst`, capped by what it said it can take.
+fn packet_size(opt: &Opt, peers: &Peers, dst: &str) -> usize {
+    match peers.get(dst) {
+        Some(caps) => std::cmp::min(opt.size, caps.max_packet_size),
+        None => opt.size,
+    }
+}
+
+/// The file listing, split into pages that each fit in `size` bytes.
+///
+/// There's always at least one page, and at least one entry per page,
+/// even if it doesn't fit.
+fn list_pages(index: &DirectoryIndex, size: usize, enc: &ReplyEncoder) -> Vec<Vec<ListEntry>> {
+    let encoded_size = |entries: &[ListEntry]| {
+        enc.encode(Message::ListReply {
+            tag: u16::MAX,
+            page: u16::MAX,
+            last: u16::MAX,
+            entries: entries.to_vec(),
+        })
+        .len()
+    };
+    let mut pages = vec![Vec::new()];
+    for f in index.list() {
+        let entry = ListEntry {
+            hash: Hash::from_hex(&f.hash).expect("index has valid hashes"),
+            name: f.name,
+        };
+        let page = pages.last_mut().unwrap();
+        page.push(entry);
+        if page.len() > 1 && encoded_size(page) > size {
+            let entry = page.pop().unwrap();
+            pages.push(vec![entry]);
+        }
+    }
+    pages
+}
+
+/// As many of `entries` as fit in a find reply of `size` bytes, but at
+/// least one.
+fn find_reply(tag: u16, entries: Vec<FindEntry>, size: usize, enc: &ReplyEncoder) -> Message {
+    let total = entries.len() as u64;
+    let mut reply = Vec::new();
+    for entry in entries {
+        reply.push(entry);
+        let encoded = enc.encode(Message::FindReply {
+            tag,
+            total,
+            entries: reply.clone(),
+        });
+        if reply.len() > 1 && encoded.len() > size {
+            reply.pop();
+            break;
+        }
+    }
+    Message::FindReply {
+        tag,
+        total,
+        entries: reply,
+    }
+}
+
+/// Ambiguous hash prefix error, with as many of the candidates as fit in
+/// `size` bytes.
+fn ambiguous_reply(tag: u16, candidates: &[Hash], size: usize, enc: &ReplyEncoder) -> Message {
+    let msg = |n| Message::Error {
+        tag,
+        reason: ErrorReason::Ambiguous,
+        candidates: candidates[..n].to_vec(),
+    };
+    let mut n = candidates.len();
+    while n > 0 && enc.encode(msg(n)).len() > size {
+        n -= 1;
+    }
+    msg(n)
+}
+
+/// A file prepared for sending to one station.
+struct Transfer {
+    /// What's FEC encoded, after compression.
+    data: Vec<u8>,
+    layout: BlockLayout,
+    compression: Compression,
+    original_size: usize,
+}
+
+/// Compression to use for sending `data` to `dst`, if any.
+///
+/// Only used if the station supports it, and it saves packets.
+fn compress(opt: &Opt, peers: &Peers, dst: &str, data: Vec<u8>) -> (Vec<u8>, Compression) {
+    let supported = peers.get(dst).map_or(0, |caps| caps.compression);
+    let compression = Compression::Deflate;
+    if supported & compression.mask() == 0 {
+        return (data, Compression::None);
+    }
+    let packets = |len: usize| len.div_ceil(packet_size(opt, peers, dst));
+    let compressed = compression.compress(&data);
+    if packets(compressed.len()) >= packets(data.len()) {
+        return (data, Compression::None);
+    }
+    debug!(
+        "Compressed {} bytes to {} with {}",
+        data.len(),
+        compressed.len(),
+        compression
+    );
+    (compressed, compression)
+}
+
+/// Header frames to send among data frames.
+struct Headers {
+    /// Encoded header message.
+    payload: Vec<u8>,
+
+    /// Data frames per header frame.
+    interval: usize,
+}
+
+fn headers(
+    opt: &Opt,
+    enc: &ReplyEncoder,
+    tag: u16,
+    id: &str,
+    transfer: &Transfer,
+) -> Option<Headers> {
+    if opt.header_interval == 0 {
+        return None;
+    }
+    Some(Headers {
+        payload: enc.encode(Message::Header {
+            tag,
+            hash: Hash::from_hex(id).expect("index has valid hashes"),
+            layout: transfer.layout,
+            compression: transfer.compression,
+            original_size: transfer.original_size,
+        }),
+        interval: opt.header_interval,
+    })
+}
+
+/// What to send when sending `id` to `dst`.
+fn lookup(
+    opt: &Opt,
+    index: &DirectoryIndex,
+    peers: &Peers,
+    dst: &str,
+    id: &str,
+) -> Result<Transfer, ErrorReason> {
+    let block = match index.get_block(id) {
+        Ok(block) => block,
+        Err(e) => {
+            warn!("Unknown block {}: {:?}", id, e);
+            return Err(ErrorReason::NotFound);
+        }
+    };
+    prepare(opt, peers, dst, id, block)
+}
+
+/// What to send when sending `length` bytes at `offset` of `id` to `dst`,
+/// and the hash of those bytes.
+///
+/// The range is cut short at the end of the file.
+fn lookup_range(
+    opt: &Opt,
+    index: &DirectoryIndex,
+    peers: &Peers,
+    dst: &str,
+    id: &str,
+    (offset, length): (u64, u64),
+) -> Result<(Transfer, String), ErrorReason> {
+    let block = match index.get_block(id) {
+        Ok(block) => block,
+        Err(e) => {
+            warn!("Unknown block {}: {:?}", id, e);
+            return Err(ErrorReason::NotFound);
+        }
+    };
+    if offset > block.len() as u64 {
+        warn!("Range at {} past the end of {}", offset, id);
+        return Err(ErrorReason::BadRange);
+    }
+    let start = offset as usize;
+    let end = start + std::cmp::min(length, (block.len() - start) as u64) as usize;
+    let part = block[start..end].to_vec();
+    let range_hash = merkle::hash(&part).to_string();
+    debug!("Range {}..{} of {} is {}", start, end, id, range_hash);
+    Ok((prepare(opt, peers, dst, id, part)?, range_hash))
+}
+
+/// Most chunk hashes to send for one tree request.
+const MAX_TREE_LEAVES: usize = 256;
+
+/// Tree replies with the chunk hashes of `data` from `first` on, each
+/// within `size` bytes.
+fn tree_replies(
+    tag: u16,
+    hash: &Hash,
+    data: &[u8],
+    first: u64,
+    size: usize,
+    codec: Codec,
+) -> Vec<Message> {
+    let reply = |first: usize, leaves| Message::TreeReply {
+        tag,
+        hash: *hash,
+        first: first as u64,
+        leaves,
+    };
+    let mut msgs = Vec::new();
+    let mut first = first as usize;
+    let mut leaves = Vec::new();
+    for leaf in merkle::leaves(data)
+        .into_iter()
+        .skip(first)
+        .take(MAX_TREE_LEAVES)
+    {
+        leaves.push(leaf);
+        if leaves.len() > 1 && reply(first, leaves.clone()).encode(codec).len() > size {
+            let last = leaves.pop().unwrap();
+            let n = leaves.len();
+            msgs.push(reply(first, std::mem::replace(&mut leaves, vec![last])));
+            first += n;
+        }
+    }
+    if !leaves.is_empty() {
+        msgs.push(reply(first, leaves));
+    }
+    msgs
+}
+
+/// Signatures of a station's older copy of a file, sent ahead of a delta
+/// request with the same tag.
+struct Basis {
+    id: String,
+    block_size: usize,
+    sigs: Vec<Option<Signature>>,
+    received: Instant,
+}
+
+/// Bases by station and tag. Kept after use, for retried requests.
+type Bases = HashMap<(String, u16), Basis>;
+
+/// Most bases to keep. The oldest is dropped to make room.
+const MAX_BASES: usize = 16;
+
+/// Largest basis to accept, in blocks.
+const MAX_BASIS_BLOCKS: u64 = 65536;
+
+/// Largest block size to accept for a basis.
+const MAX_BASIS_BLOCK_SIZE: u64 = 1 << 20;
+
+/// Save signatures `first..` of a basis.
+fn add_basis(
+    bases: &mut Bases,
+    key: (String, u16),
+    id: &str,
+    block_size: u64,
+    first: u64,
+    sigs: &[Signature],
+) {
+    if block_size == 0
+        || block_size > MAX_BASIS_BLOCK_SIZE
+        || first
+            .checked_add(sigs.len() as u64)
+            .is_none_or(|end| end > MAX_BASIS_BLOCKS)
+    {
+        warn!("Ignoring basis from {} with too many blocks", key.0);
+        return;
+    }
+    if !bases.contains_key(&key) && bases.len() >= MAX_BASES {
+        let oldest = bases
+            .iter()
+            .min_by_key(|(_, b)| b.received)
+            .map(|(k, _)| k.clone())
+            .expect("bases is not empty");
+        bases.remove(&oldest);
+    }
+    let basis = bases.entry(key).or_insert_with(|| Basis {
+        id: id.to_string(),
+        block_size: block_size as usize,
+        sigs: Vec::new(),
+        received: Instant::now(),
+    });
+    if basis.id != id || basis.block_size != block_size as usize {
+        basis.id = id.to_string();
+        basis.block_size = block_size as usize;
+        basis.sigs.clear();
+    }
+    basis.received = Instant::now();
+    let first = first as usize;
+    if basis.sigs.len() < first + sigs.len() {
+        basis.sigs.resize(first + sigs.len(), None);
+    }
+    for (n, sig) in sigs.iter().enumerate() {
+        basis.sigs[first + n] = Some(*sig);
+    }
+}
+
+/// What to send when sending the differences between `dst`'s older copy
+/// of `id` and `id` itself, and the hash of the delta.
+///
+/// Signatures that never arrived just make the delta larger.
+#[allow(clippy::too_many_arguments)]
+fn lookup_delta(
+    opt: &Opt,
+    index: &DirectoryIndex,
+    peers: &Peers,
+    bases: &Bases,
+    dst: &str,
+    tag: u16,
+    id: &str,
+    (block_size, blocks): (u64, u64),
+) -> Result<(Transfer, u64, String), ErrorReason> {
+    let block = match index.get_block(id) {
+        Ok(block) => block,
+        Err(e) => {
+            warn!("Unknown block {}: {:?}", id, e);
+            return Err(ErrorReason::NotFound);
+        }
+    };
+    if block_size == 0 || block_size > MAX_BASIS_BLOCK_SIZE || blocks > MAX_BASIS_BLOCKS {
+        warn!(
+            "Basis of {} blocks of {} bytes too large",
+            blocks, block_size
+        );
+        return Err(ErrorReason::TooLarge);
+    }
+    let mut sigs = match bases.get(&(dst.to_string(), tag)) {
+        Some(basis) if basis.id == id && basis.block_size as u64 == block_size => {
+            basis.sigs.clone()
+        }
+        _ => Vec::new(),
+    };
+    sigs.resize(blocks as usize, None);
+    let d = delta::delta(&block, block_size as usize, &sigs);
+    let delta_hash = merkle::hash(&d).to_string();
+    info!(
+        "Delta of {} against {} of {} blocks is {} bytes",
+        id,
+        dst,
+        sigs.iter().filter(|s| s.is_some()).count(),
+        d.len()
+    );
+    Ok((
+        prepare(opt, peers, dst, id, d)?,
+        block.len() as u64,
+        delta_hash,
+    ))
+}
+
+/// Prepare `block` of `id` for sending to `dst`.
+fn prepare(
+    opt: &Opt,
+    peers: &Peers,
+    dst: &str,
+    id: &str,
+    block: Vec<u8>,
+) -> Result<Transfer, ErrorReason> {
+    let original_size = block.len();
+    let (data, compression) = compress(opt, peers, dst, block);
+    let packet_size = packet_size(opt, peers, dst);
+    let Some(layout) = BlockLayout::checked(data.len(), packet_size, opt.block_symbols) else {
+        warn!(
+            "Can't split {} bytes of {} into source blocks of {} byte symbols",
+            data.len(),
+            id,
+            packet_size
+        );
+        return Err(ErrorReason::TooLarge);
+    };
+    Ok(Transfer {
+        data,
+        layout,
+        compression,
+        original_size,
+    })
+}
+
+async fn send_error(
+    client: &mut Transport,
+    parser: &mut Ax25Codec,
+    dst: &str,
+    src: &str,
+    tag: u16,
+    reason: ErrorReason,
+    enc: &ReplyEncoder,
+) -> Result<(), UploaderError> {
+    info!("Rejecting request {} from {}: {}", tag, dst, reason);
+    let msg = Message::Error {
+        tag,
+        reason,
+        candidates: Vec::new(),
+    };
+    let reply = make_packet(parser, dst, src, enc.encode(msg)).await?;
+    client.send(reply).await?;
+    Ok(())
+}
+
+/*
+* Data frames being sent for a file, shared by every station that asked
+* for it with the same parameters. Fountain codes make any encoding
+* symbol useful to anyone still missing the block, so a second request
+* only adds what the symbols already queued don't cover.
+*/
+struct Session {
+    hash: String,
+    tag: u16,
+
+    /// Where data is sent. The requester, or the broadcast address once
+    /// more than one station is receiving.
+    dst: String,
+
+    /// Stations, and the tags they requested with.
+    members: Vec<(String, u16)>,
+
+    transfer: Transfer,
+    headers: Option<Headers>,
+
+    /// Block and ESI of encoding symbols still to send.
+    queue: VecDeque<(u16, u16)>,
+
+    /// ESIs sent or queued, per source block.
+    used: Vec<EsiSet>,
+
+    /// Data frames sent so far.
+    sent: usize,
+
+    encoders: HashMap<u16, raptor_code::SourceBlockEncoder>,
+}
+
+impl Session {
+    fn new(
+        opt: &Opt,
+        enc: &ReplyEncoder,
+        dst: &str,
+        tag: u16,
+        hash: &str,
+        transfer: Transfer,
+    ) -> Session {
+        Session {
+            hash: hash.to_string(),
+            tag,
+            dst: dst.to_string(),
+            members: vec![(dst.to_string(), tag)],
+            headers: headers(opt, enc, tag, hash, &transfer),
+            queue: VecDeque::new(),
+            used: vec![EsiSet::new(); transfer.layout.blocks()],
+            sent: 0,
+            encoders: HashMap::new(),
+            transfer,
+        }
+    }
+
+    /// Queue what's needed on top of what's already queued, for someone
+    /// who asked for `txlist`.
+    fn add(&mut self, txlist: Vec<(u16, u16)>) {
+        let mut queued = vec![0; self.used.len()];
+        for (block, _) in &self.queue {
+            queued[*block as usize] += 1;
+        }
+        let mut wanted = vec![Vec::new(); self.used.len()];
+        for (block, esi) in txlist {
+            wanted[block as usize].push(esi);
+        }
+        let mut added = Vec::new();
+        for (n, esis) in wanted.into_iter().enumerate() {
+            let extra = esis.len().saturating_sub(queued[n]);
+            let used = &mut self.used[n];
+            let mut new = Vec::new();
+            for esi in esis {
+                if new.len() == extra {
+                    break;
+                }
+                if !used.contains(esi) {
+                    used.insert(esi);
+                    new.push(esi);
+                }
+            }
+            // Asked for symbols that someone else already got. Send new
+            // ones instead.
+            for esi in used.missing(extra - new.len()) {
+                used.insert(esi);
+                new.push(esi);
+            }
+            added.extend(new.into_iter().map(|esi| (n as u16, esi)));
+        }
+        added.shuffle(&mut rand::rng());
+        debug!(
+            "Queued {} packets for session {}, {} in total",
+            added.len(),
+            self.tag,
+            self.queue.len() + added.len()
+        );
+        self.queue.extend(added);
+    }
+
+    /// Send the next data frame, preceded by a header frame if it's time
+    /// for one.
+    async fn send_next(
+        &mut self,
+        client: &mut Transport,
+        parser: &mut Ax25Codec,
+        src: &str,
+        codec: Codec,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (block, esi) = match self.queue.pop_front() {
+            Some(next) => next,
+            None => return Ok(()),
+        };
+        if let Some(headers) = &self.headers {
+            if self.sent.is_multiple_of(headers.interval) {
+                let request = make_packet(parser, &self.dst, src, headers.payload.clone()).await?;
+                client.send(request).await?;
+            }
+        }
+        self.sent += 1;
+        let layout = &self.transfer.layout;
+        let encoder = match self.encoders.entry(block) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let n = block as usize;
+                e.insert(raptor_code::SourceBlockEncoder::new(
+                    &layout.padded_block(&self.transfer.data, n),
+                    layout.source_symbols(n),
+                )?)
+            }
+        };
+        let msg = Message::Data {
+            tag: self.tag,
+            block,
+            esi,
+            symbol: encoder.fountain(esi as u32),
+        };
+        let request = make_packet(parser, &self.dst, src, msg.encode(codec)).await?;
+        client.send(request).await?;
+        Ok(())
+    }
+}
+
+/// Send `txlist` of `id` to `dst`, as part of a session already sending
+/// the same thing if there is one.
+#[allow(clippy::too_many_arguments)]
+async fn schedule(
+    client: &mut Transport,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    enc: &ReplyEncoder,
+    sessions: &mut Vec<Session>,
+    dst: &str,
+    tag: u16,
+    id: &str,
+    transfer: Transfer,
+    txlist: Vec<(u16, u16)>,
+) -> Result<(), UploaderError> {
+    let session = sessions.iter_mut().find(|s| {
+        s.hash == id
+            && s.transfer.layout == transfer.layout
+            && s.transfer.compression == transfer.compression
+    });
+    let session = match session {
+        Some(s) => s,
+        None => {
+            let mut session = Session::new(opt, enc, dst, tag, id, transfer);
+            session.add(txlist);
+            sessions.push(session);
+            return Ok(());
+        }
+    };
+    let member = (dst.to_string(), tag);
+    if !session.members.contains(&member) {
+        info!(
+            "Request {} from {} joins session {} for {}",
+            tag, dst, session.tag, id
+        );
+        session.members.push(member);
+        if session.dst != dst {
+            session.dst = opt.broadcast_dst.clone();
+        }
+    }
+    // Again on every retry, in case the last one was lost.
+    if tag != session.tag {
+        let msg = Message::Join {
+            tag,
+            session: session.tag,
+        };
+        let reply = make_packet(parser, dst, &opt.source, enc.encode(msg)).await?;
+        client.send(reply).await?;
+    }
+    session.add(txlist);
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_requests(
-    client: &mut RouterServiceClient<tonic::transport::Channel>,
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
+    client: &mut Transport,
+    parser: &mut Ax25Codec,
     opt: &Opt,
     index: &DirectoryIndex,
+    peers: &mut Peers,
+    enc: &ReplyEncoder,
+    sessions: &mut Vec<Session>,
+    bases: &mut Bases,
     reqs: &[Request],
 ) -> Result<(), UploaderError> {
-    let nb_repair = u32::try_from(opt.repair).unwrap();
     for r in reqs {
         match r {
+            Request::Basis {
+                dst,
+                tag,
+                id,
+                block_size,
+                first,
+                sigs,
+            } => add_basis(bases, (dst.clone(), *tag), id, *block_size, *first, sigs),
             Request::Get {
                 dst,
                 frequency: _,
                 tag,
-                existing: _,
+                existing,
                 id,
-            } => match index.get_block(id) {
-                Ok(block) => {
-                    handle_get(
+                range: _,
+                delta: Some(basis),
+            } => match lookup_delta(opt, index, peers, bases, dst, *tag, id, *basis) {
+                Ok((t, file_size, delta_hash)) => {
+                    let msg = Message::DeltaReply {
+                        tag: *tag,
+                        hash: Hash::from_hex(id).expect("index has valid hashes"),
+                        file_size,
+                        delta_hash: Hash::from_hex(&delta_hash)
+                            .expect("merkle root is a valid hash"),
+                        layout: t.layout,
+                        compression: t.compression,
+                        original_size: t.original_size,
+                    };
+                    let reply = make_packet(parser, dst, &opt.source, enc.encode(msg)).await?;
+                    for _ in 0..opt.repeat {
+                        client.send(reply.clone()).await?;
+                    }
+                    // Like a range, the delta is sent as a file of its own.
+                    let txlist = get_txlist(&t.layout, opt.repair, *existing as usize);
+                    schedule(
                         client,
                         parser,
-                        &block,
+                        opt,
+                        enc,
+                        sessions,
                         dst,
-                        opt.source.clone(),
                         *tag,
-                        opt.size,
-                        nb_repair,
+                        &delta_hash,
+                        t,
+                        txlist,
                     )
                     .await?;
                 }
-                Err(e) => {
-                    warn!("Unknown block {}: {:?}", id, e);
+                Err(reason) => {
+                    send_error(client, parser, dst, &opt.source, *tag, reason, enc).await?;
                 }
             },
-            Request::Meta { dst, hash } => match index.get_block(hash) {
-                Ok(block) => {
+            Request::Get {
+                dst,
+                frequency: _,
+                tag,
+                existing,
+                id,
+                range: Some(range),
+                delta: None,
+            } => match lookup_range(opt, index, peers, dst, id, *range) {
+                Ok((t, range_hash)) => {
+                    let msg = Message::RangeReply {
+                        tag: *tag,
+                        hash: Hash::from_hex(id).expect("index has valid hashes"),
+                        offset: range.0,
+                        range_hash: Hash::from_hex(&range_hash)
+                            .expect("merkle root is a valid hash"),
+                        layout: t.layout,
+                        compression: t.compression,
+                        original_size: t.original_size,
+                    };
+                    let reply = make_packet(parser, dst, &opt.source, enc.encode(msg)).await?;
+                    for _ in 0..opt.repeat {
+                        client.send(reply.clone()).await?;
+                    }
+                    // The range is sent as a file of its own, with its own
+                    // hash, so receivers can check it.
+                    let txlist = get_txlist(&t.layout, opt.repair, *existing as usize);
+                    schedule(
+                        client,
+                        parser,
+                        opt,
+                        enc,
+                        sessions,
+                        dst,
+                        *tag,
+                        &range_hash,
+                        t,
+                        txlist,
+                    )
+                    .await?;
+                }
+                Err(reason) => {
+                    send_error(client, parser, dst, &opt.source, *tag, reason, enc).await?;
+                }
+            },
+            Request::Get {
+                dst,
+                frequency: _,
+                tag,
+                existing,
+                id,
+                range: None,
+                delta: None,
+            } => match lookup(opt, index, peers, dst, id) {
+                Ok(t) => {
+                    debug!("Handling GET, downloader has {} bytes", existing);
+                    let txlist = get_txlist(&t.layout, opt.repair, *existing as usize);
+                    schedule(client, parser, opt, enc, sessions, dst, *tag, id, t, txlist).await?;
+                }
+                Err(reason) => {
+                    send_error(client, parser, dst, &opt.source, *tag, reason, enc).await?;
+                }
+            },
+            Request::Sack {
+                dst,
+                tag,
+                id,
+                blocks,
+            } => match lookup(opt, index, peers, dst, id) {
+                Ok(t) => {
+                    let txlist = sack_txlist(&t.layout, blocks);
+                    schedule(client, parser, opt, enc, sessions, dst, *tag, id, t, txlist).await?;
+                }
+                Err(reason) => {
+                    send_error(client, parser, dst, &opt.source, *tag, reason, enc).await?;
+                }
+            },
+            Request::Meta { dst, tag, hash } => match lookup(opt, index, peers, dst, hash) {
+                Ok(t) => {
                     handle_meta(
                         client,
                         parser,
-                        &block,
                         dst,
                         opt.source.clone(),
-                        hash.to_string(),
-                        opt.size,
+                        *tag,
+                        hash,
+                        &t,
+                        index.info(hash).expect("lookup found the file"),
                         opt.repeat,
+                        enc,
                     )
                     .await?;
                 }
-                Err(e) => {
-                    warn!("Unknown block {}: {:?}", hash, e);
+                Err(reason) => {
+                    send_error(client, parser, dst, &opt.source, *tag, reason, enc).await?;
                 }
             },
-            Request::List { dst, tag } => {
-                // TODO: support longer file listings.
-                let mut txt = format!("l {}\n", tag);
-                for f in index.list() {
-                    txt.push_str(&format!("{} {}\n", f.hash, f.name));
+            Request::Tree {
+                dst,
+                tag,
+                id,
+                first,
+            } => match index.get_block(id) {
+                Ok(block) => {
+                    let hash = Hash::from_hex(id).expect("index has valid hashes");
+                    let size = packet_size(opt, peers, dst);
+                    let msgs = tree_replies(*tag, &hash, &block, *first, size, opt.codec());
+                    debug!("Sending {} tree frames of {} to {}", msgs.len(), id, dst);
+                    for msg in msgs {
+                        // Not signed, since the hashes are checked against
+                        // the root.
+                        let reply =
+                            make_packet(parser, dst, &opt.source, msg.encode(opt.codec())).await?;
+                        client.send(reply).await?;
+                    }
                 }
-                let reply = make_packet(parser, dst, &opt.source, txt).await?;
-                client
-                    .send(tonic::Request::new(ax25ms::SendRequest {
-                        frame: Some(ax25ms::Frame { payload: reply }),
-                    }))
-                    .await?;
-                let reply = make_packet(parser, dst, &opt.source, format!("l {}", tag)).await?;
-                client
-                    .send(tonic::Request::new(ax25ms::SendRequest {
-                        frame: Some(ax25ms::Frame { payload: reply }),
-                    }))
+                Err(e) => {
+                    warn!("Unknown block {}: {:?}", id, e);
+                    send_error(
+                        client,
+                        parser,
+                        dst,
+                        &opt.source,
+                        *tag,
+                        ErrorReason::NotFound,
+                        enc,
+                    )
                     .await?;
+                }
+            },
+            Request::List { dst, tag, pages } => {
+                let all = list_pages(index, packet_size(opt, peers, dst), enc);
+                let last = (all.len() - 1) as u16;
+                for (page, entries) in all.into_iter().enumerate() {
+                    let page = page as u16;
+                    if !pages.is_empty() && !pages.contains(&page) {
+                        continue;
+                    }
+                    let msg = Message::ListReply {
+                        tag: *tag,
+                        page,
+                        last,
+                        entries,
+                    };
+                    let reply = make_packet(parser, dst, &opt.source, enc.encode(msg)).await?;
+                    client.send(reply).await?;
+                }
+            }
+            Request::Find { dst, tag, pattern } => {
+                let found = index.find(pattern);
+                debug!("Found {} matches for {:?}", found.len(), pattern);
+                let msg = find_reply(*tag, found, packet_size(opt, peers, dst), enc);
+                let reply = make_packet(parser, dst, &opt.source, enc.encode(msg)).await?;
+                client.send(reply).await?;
+            }
+            Request::Ambiguous {
+                dst,
+                tag,
+                candidates,
+            } => {
+                info!(
+                    "Rejecting request {} from {}: {} files match",
+                    tag,
+                    dst,
+                    candidates.len()
+                );
+                let msg = ambiguous_reply(*tag, candidates, packet_size(opt, peers, dst), enc);
+                let reply = make_packet(parser, dst, &opt.source, enc.encode(msg)).await?;
+                client.send(reply).await?;
+            }
+            Request::Reject { dst, tag, reason } => {
+                send_error(client, parser, dst, &opt.source, *tag, *reason, enc).await?;
+            }
+            Request::Caps { dst, tag, caps } => {
+                let mut ours = Capabilities::ours(opt.size);
+                if opt.no_compression {
+                    ours.compression = 0;
+                }
+                peers.insert(dst.clone(), ours.common(caps));
+                let msg = Message::CapsReply {
+                    tag: *tag,
+                    caps: ours,
+                };
+                let reply = make_packet(parser, dst, &opt.source, enc.encode(msg)).await?;
+                client.send(reply).await?;
             }
         }
     }
     Ok(())
 }
 
+/// A file being broadcast.
+struct Broadcast<'a> {
+    hash: &'a str,
+    tag: u16,
+    transfer: Transfer,
+
+    /// Next ESI to send, per source block.
+    next_esi: Vec<u16>,
+}
+
+/*
+* Send files to everyone listening, over and over, without waiting for
+* requests. Every round sends the metadata, and new encoding symbols for
+* every file, so that receivers keep getting closer to decoding.
+*/
+async fn broadcast(
+    client: &mut Transport,
+    parser: &mut Ax25Codec,
+    opt: &Opt,
+    index: &DirectoryIndex,
+    enc: &ReplyEncoder,
+) -> Result<(), UploaderError> {
+    // Nobody to negotiate with, so assume receivers support everything
+    // we do.
+    let dst = &opt.broadcast_dst;
+    let mut caps = Capabilities::ours(opt.size);
+    if opt.no_compression {
+        caps.compression = 0;
+    }
+    let peers = Peers::from([(dst.clone(), caps)]);
+
+    let mut files = Vec::new();
+    for hash in &opt.broadcast {
+        let transfer = match lookup(opt, index, &peers, dst, hash) {
+            Ok(t) => t,
+            Err(reason) => {
+                warn!("Can't broadcast {}: {}", hash, reason);
+                return Err(UploaderError::HashNotFound);
+            }
+        };
+        files.push(Broadcast {
+            hash,
+            tag: rand::rng().random::<u16>(),
+            next_esi: vec![0; transfer.layout.blocks()],
+            transfer,
+        });
+    }
+
+    let mut round = 0;
+    loop {
+        for f in files.iter_mut() {
+            info!("Broadcasting {} with tag {}", f.hash, f.tag);
+            handle_meta(
+                client,
+                parser,
+                dst,
+                opt.source.clone(),
+                f.tag,
+                f.hash,
+                &f.transfer,
+                index.info(f.hash).expect("lookup found the file"),
+                opt.repeat,
+                enc,
+            )
+            .await?;
+
+            // Enough for a receiver that heard nothing before to decode,
+            // with a bit of loss.
+            let layout = &f.transfer.layout;
+            let mut txlist = Vec::new();
+            for (n, next) in f.next_esi.iter_mut().enumerate() {
+                let count = (layout.source_symbols(n) as f32 * 1.2 + 2.0) as usize;
+                for _ in 0..count {
+                    txlist.push((n as u16, *next));
+                    *next = next.wrapping_add(1);
+                }
+            }
+            txlist.shuffle(&mut rand::rng());
+            transmit(
+                client,
+                parser,
+                dst,
+                opt.source.clone(),
+                f.tag,
+                layout,
+                &f.transfer.data,
+                txlist,
+                headers(opt, enc, f.tag, f.hash, &f.transfer).as_ref(),
+                opt.codec(),
+            )
+            .await?;
+        }
+        round += 1;
+        if round == opt.broadcast_rounds {
+            return Ok(());
+        }
+        task::sleep(Duration::from_secs_f32(opt.broadcast_pause)).await;
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<(), UploaderError> {
     let opt = Opt::parse();
@@ -552,27 +1872,157 @@ async fn main() -> Result<(), UploaderError> {
         .unwrap();
 
     let index = DirectoryIndex::new(&opt.input).unwrap();
+    let mut peers = Peers::new();
+    let enc = ReplyEncoder {
+        codec: opt.codec(),
+        key: match &opt.key {
+            Some(path) => {
+                let key = load_or_create_key(path)?;
+                info!("Signing replies with key {}", public_key_hex(&key));
+                Some(key)
+            }
+            None => None,
+        },
+    };
 
     info!("Running…");
-    let mut client = RouterServiceClient::connect(opt.router.clone()).await?;
-    let mut parser = Ax25ParserClient::connect(opt.parser.clone()).await?;
+    let (mut client, frames) = match &opt.router {
+        Some(router) => Transport::router(router.clone(), router.clone()).await?,
+        None => Transport::kiss(&opt.kiss).await?,
+    };
+    let mut parser = Ax25Codec::connect(opt.parser.clone()).await?;
 
-    info!("Awaiting requests…");
-    let mut stream = client
-        .stream_frames(ax25ms::StreamRequest {})
-        .await?
-        .into_inner();
+    if !opt.broadcast.is_empty() {
+        return broadcast(&mut client, &mut parser, &opt, &index, &enc).await;
+    }
 
+    info!("Awaiting requests…");
+    let mut requests = start_requests(frames, parser.clone());
+    let mut sessions = Vec::new();
+    let mut bases = Bases::new();
+    let mut next = 0;
     loop {
-        let (src, req) = get_request(&mut stream, &mut parser).await?;
-
-        match parse_request(&src, &req) {
-            Ok(reqs) => {
-                process_requests(&mut client, &mut parser, &opt, &index, &reqs).await?;
-            }
-            Err(e) => {
-                debug!("Unknown block: {:?}", e);
+        // Handle all requests that have arrived, waiting for one only if
+        // there's nothing to send.
+        let mut frames = Vec::new();
+        if sessions.is_empty() {
+            frames.push(
+                requests
+                    .recv()
+                    .await
+                    .ok_or(UploaderError::StreamError("request stream ended".into()))?,
+            );
+        }
+        while let Ok(frame) = requests.try_recv() {
+            frames.push(frame);
+        }
+        for (src, req) in frames {
+            match parse_request(&index, &src, &req) {
+                Ok(reqs) => {
+                    process_requests(
+                        &mut client,
+                        &mut parser,
+                        &opt,
+                        &index,
+                        &mut peers,
+                        &enc,
+                        &mut sessions,
+                        &mut bases,
+                        &reqs,
+                    )
+                    .await?;
+                }
+                Err(e) => {
+                    debug!("Invalid request: {}", e);
+                }
             }
         }
+
+        // Take turns sending data for each session.
+        if sessions.is_empty() {
+            continue;
+        }
+        next %= sessions.len();
+        sessions[next]
+            .send_next(&mut client, &mut parser, &opt.source, opt.codec())
+            .await?;
+        if sessions[next].queue.is_empty() {
+            let done = sessions.remove(next);
+            debug!("Session {} for {} done", done.tag, done.hash);
+        } else {
+            next += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sig(n: u32) -> Signature {
+        Signature { weak: n, strong: n }
+    }
+
+    #[test]
+    fn add_basis_bounds() {
+        let mut bases = Bases::new();
+        let key = ("M0XXX".to_string(), 1);
+        add_basis(&mut bases, key.clone(), "id", 256, u64::MAX, &[sig(1)]);
+        add_basis(
+            &mut bases,
+            key.clone(),
+            "id",
+            256,
+            MAX_BASIS_BLOCKS,
+            &[sig(1)],
+        );
+        add_basis(&mut bases, key.clone(), "id", 0, 0, &[sig(1)]);
+        assert!(bases.is_empty());
+
+        add_basis(&mut bases, key.clone(), "id", 256, 2, &[sig(1), sig(2)]);
+        assert_eq!(bases[&key].sigs, [None, None, Some(sig(1)), Some(sig(2))]);
+    }
+
+    #[test]
+    fn glob() {
+        for (pattern, name) in [
+            ("", ""),
+            ("*", ""),
+            ("*", "a.txt"),
+            ("*.txt", "a.txt"),
+            ("*.txt", ".txt"),
+            ("a*", "a"),
+            ("a*", "abc"),
+            ("a**", "abc"),
+            ("?", "a"),
+            ("a?c", "abc"),
+            ("??*", "ab"),
+            // Backtracking past earlier partial matches.
+            ("*ab", "aab"),
+            ("*abc", "ababc"),
+            ("a*b*c", "aXbXbXc"),
+            ("*a*a*a", "aaaa"),
+            ("*.tar.gz", "x.tar.tar.gz"),
+            ("dir/*", "dir/file"),
+        ] {
+            assert!(glob_match(pattern, name), "{pattern:?} {name:?}");
+        }
+        for (pattern, name) in [
+            ("", "a"),
+            ("a", ""),
+            ("?", ""),
+            ("a?c", "ac"),
+            ("*.txt", "a.txt.gz"),
+            ("a*", "ba"),
+            ("*ab", "aba"),
+            ("a*b*c", "aXbXbX"),
+            ("*a*a*a", "aa"),
+            // Neither matches a `/`.
+            ("*", "dir/file"),
+            ("dir?file", "dir/file"),
+            ("*file", "dir/file"),
+        ] {
+            assert!(!glob_match(pattern, name), "{pattern:?} {name:?}");
+        }
     }
 }
@@ -0,0 +1,142 @@
+use std::io::{Read, Write};
+
+/// Compression codecs, as a bitmask in capabilities.
+pub const COMPRESSION_DEFLATE: u64 = 1;
+
+///
+/// How a file is compressed before FEC encoding.
+///
+/// The hash is always of the uncompressed file, so the downloader can
+/// verify what it ends up with.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Deflate,
+}
+
+impl Compression {
+    /// Code in metadata replies.
+    pub fn code(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::Deflate => 1,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Compression> {
+        match code {
+            0 => Some(Self::None),
+            1 => Some(Self::Deflate),
+            _ => None,
+        }
+    }
+
+    /// Bit in the capabilities compression mask. Zero for no compression,
+    /// which is always supported.
+    pub fn mask(&self) -> u64 {
+        match self {
+            Self::None => 0,
+            Self::Deflate => COMPRESSION_DEFLATE,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Self::None => data.to_vec(),
+            Self::Deflate => {
+                let mut e =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+                e.write_all(data).expect("writing to a Vec");
+                e.finish().expect("writing to a Vec")
+            }
+        }
+    }
+
+    /// Decompress, refusing to produce more than `max_size` bytes.
+    pub fn decompress(&self, data: &[u8], max_size: usize) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Deflate => {
+                let mut out = Vec::new();
+                flate2::read::DeflateDecoder::new(data)
+                    .take(max_size as u64 + 1)
+                    .read_to_end(&mut out)?;
+                if out.len() > max_size {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "decompressed data larger than expected",
+                    ));
+                }
+                Ok(out)
+            }
+        }
+    }
+}
+
+///
+/// Decompresses data fed to it a piece at a time, in order, so that the
+/// start of a file can be checked before the rest has arrived.
+///
+pub struct Decompressor {
+    inflate: Option<flate2::Decompress>,
+    max_size: usize,
+    total_out: usize,
+}
+
+impl Decompressor {
+    /// Decompressor refusing to produce more than `max_size` bytes.
+    pub fn new(compression: Compression, max_size: usize) -> Decompressor {
+        Decompressor {
+            inflate: match compression {
+                Compression::None => None,
+                Compression::Deflate => Some(flate2::Decompress::new(false)),
+            },
+            max_size,
+            total_out: 0,
+        }
+    }
+
+    /// Decompress the next piece, adding what comes out of it to `out`.
+    pub fn feed(&mut self, data: &[u8], out: &mut Vec<u8>) -> std::io::Result<()> {
+        let start = out.len();
+        match &mut self.inflate {
+            None => out.extend(data),
+            Some(inflate) => {
+                let mut data = data;
+                loop {
+                    out.reserve(data.len() + 4096);
+                    let before = inflate.total_in();
+                    let status =
+                        inflate.decompress_vec(data, out, flate2::FlushDecompress::None)?;
+                    data = &data[(inflate.total_in() - before) as usize..];
+                    // Done when the input is used up and the output
+                    // wasn't cut short for lack of space.
+                    if status == flate2::Status::StreamEnd
+                        || (data.is_empty() && out.len() < out.capacity())
+                    {
+                        break;
+                    }
+                }
+            }
+        }
+        self.total_out += out.len() - start;
+        if self.total_out > self.max_size {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "decompressed data larger than expected",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::None => write!(f, "none"),
+            Self::Deflate => write!(f, "deflate"),
+        }
+    }
+}
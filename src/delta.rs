@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+
+use crate::protocol::{ProtocolError, Reader, Writer};
+
+///
+/// Checksums of one block of the downloader's older copy of a file.
+///
+/// `weak` is the rsync rolling checksum, that the uploader can cheaply
+/// check at every offset of the new file. `strong` is the first four
+/// bytes of the SHA-256, checked only where the weak one matches. The
+/// result is verified against the full hash anyway, so this only needs
+/// to make false matches rare, not impossible.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signature {
+    pub weak: u32,
+    pub strong: u32,
+}
+
+// Ops in a delta.
+const OP_LITERAL: u8 = 0;
+const OP_COPY: u8 = 1;
+
+/// Smallest block size to use.
+const MIN_BLOCK_SIZE: usize = 256;
+
+/// Block size to use for an older copy of `len` bytes.
+///
+/// Bigger blocks mean fewer signatures to send, smaller ones mean less
+/// to send for each change.
+pub fn block_size(len: usize) -> usize {
+    std::cmp::max(MIN_BLOCK_SIZE, len.isqrt().next_multiple_of(16))
+}
+
+/// Signatures of every whole block of `basis`. A shorter block at the end
+/// is left out, and sent as part of the delta if needed.
+pub fn signatures(basis: &[u8], block_size: usize) -> Vec<Signature> {
+    basis
+        .chunks_exact(block_size)
+        .map(|block| Signature {
+            weak: Rolling::new(block).sum(),
+            strong: strong(block),
+        })
+        .collect()
+}
+
+fn strong(block: &[u8]) -> u32 {
+    u32::from_str_radix(&sha256::digest(block)[..8], 16).expect("sha256 is hex")
+}
+
+/// rsync rolling checksum, of a window sliding over the data.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Rolling {
+        let len = window.len() as u32;
+        let mut r = Rolling { a: 0, b: 0, len };
+        for (i, x) in window.iter().enumerate() {
+            r.a = r.a.wrapping_add(*x as u32);
+            r.b = r.b.wrapping_add((len - i as u32).wrapping_mul(*x as u32));
+        }
+        r
+    }
+
+    /// Slide the window one byte, from `out` to `inn`.
+    fn roll(&mut self, out: u8, inn: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inn as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn sum(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+///
+/// Instructions for making `data` out of the older copy the signatures
+/// are of.
+///
+/// The delta is a sequence of ops, each either `0 len:varint` followed
+/// by that many bytes to use as is, or `1 index:varint count:varint` to
+/// copy `count` blocks of the older copy, starting at block `index`.
+/// Missing signatures, from lost frames, just never match.
+///
+pub fn delta(data: &[u8], block_size: usize, sigs: &[Option<Signature>]) -> Vec<u8> {
+    let mut blocks: HashMap<u32, Vec<(usize, u32)>> = HashMap::new();
+    for (n, sig) in sigs.iter().enumerate() {
+        if let Some(sig) = sig {
+            blocks.entry(sig.weak).or_default().push((n, sig.strong));
+        }
+    }
+
+    let mut w = Writer(Vec::new());
+    let mut literal_start = 0;
+    let mut copy: Option<(usize, usize)> = None;
+    let mut pos = 0;
+    let mut rolling = data.get(..block_size).map(Rolling::new);
+    while let Some(r) = &mut rolling {
+        let window = &data[pos..pos + block_size];
+        let found = blocks.get(&r.sum()).and_then(|candidates| {
+            let strong = strong(window);
+            candidates
+                .iter()
+                .find(|(_, s)| *s == strong)
+                .map(|(n, _)| *n)
+        });
+        match found {
+            Some(n) => {
+                if literal_start < pos {
+                    flush_copy(&mut w, &mut copy);
+                    w.u8(OP_LITERAL);
+                    w.bytes(&data[literal_start..pos]);
+                }
+                copy = match copy {
+                    // Consecutive blocks are copied in one go.
+                    Some((first, count)) if first + count == n && literal_start == pos => {
+                        Some((first, count + 1))
+                    }
+                    _ => {
+                        flush_copy(&mut w, &mut copy);
+                        Some((n, 1))
+                    }
+                };
+                pos += block_size;
+                literal_start = pos;
+                rolling = data.get(pos..pos + block_size).map(Rolling::new);
+            }
+            None => match data.get(pos + block_size) {
+                Some(inn) => {
+                    r.roll(data[pos], *inn);
+                    pos += 1;
+                }
+                None => rolling = None,
+            },
+        }
+    }
+    if literal_start < data.len() {
+        flush_copy(&mut w, &mut copy);
+        w.u8(OP_LITERAL);
+        w.bytes(&data[literal_start..]);
+    }
+    flush_copy(&mut w, &mut copy);
+    w.0
+}
+
+fn flush_copy(w: &mut Writer, copy: &mut Option<(usize, usize)>) {
+    if let Some((first, count)) = copy.take() {
+        w.u8(OP_COPY);
+        w.varint(first as u64);
+        w.varint(count as u64);
+    }
+}
+
+/// Make the new file from the older copy and a delta, refusing to make
+/// one larger than `max_size`.
+pub fn apply(
+    basis: &[u8],
+    block_size: usize,
+    delta: &[u8],
+    max_size: usize,
+) -> Result<Vec<u8>, ProtocolError> {
+    let mut out = Vec::new();
+    let mut r = Reader(delta);
+    while !r.is_empty() {
+        let part = match r.u8()? {
+            OP_LITERAL => r.bytes()?,
+            OP_COPY => {
+                let first = r.usize()?;
+                let count = r.usize()?;
+                first
+                    .checked_add(count)
+                    .and_then(|end| end.checked_mul(block_size))
+                    .and_then(|end| basis.get(first * block_size..end))
+                    .ok_or(ProtocolError::Invalid(format!(
+                        "copy of blocks {first}+{count} outside the older copy"
+                    )))?
+            }
+            op => return Err(ProtocolError::Invalid(format!("unknown delta op {op}"))),
+        };
+        if out.len() + part.len() > max_size {
+            return Err(ProtocolError::Invalid(
+                "delta makes a larger file than expected".to_string(),
+            ));
+        }
+        out.extend(part);
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BS: usize = 256;
+
+    /// Data that doesn't repeat within a block.
+    fn data(len: usize, seed: u32) -> Vec<u8> {
+        let mut x = seed;
+        (0..len)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (x >> 16) as u8
+            })
+            .collect()
+    }
+
+    fn sigs(basis: &[u8]) -> Vec<Option<Signature>> {
+        signatures(basis, BS).into_iter().map(Some).collect()
+    }
+
+    fn copy(first: u64, count: u64) -> Vec<u8> {
+        let mut w = Writer(Vec::new());
+        w.u8(OP_COPY);
+        w.varint(first);
+        w.varint(count);
+        w.0
+    }
+
+    #[test]
+    fn rolling() {
+        let d = data(BS * 3, 1);
+        let mut r = Rolling::new(&d[..BS]);
+        for pos in 1..=BS * 2 {
+            r.roll(d[pos - 1], d[pos + BS - 1]);
+            assert_eq!(r.sum(), Rolling::new(&d[pos..pos + BS]).sum(), "{pos}");
+        }
+    }
+
+    #[test]
+    fn unchanged() {
+        let basis = data(BS * 10, 1);
+        let d = delta(&basis, BS, &sigs(&basis));
+        assert_eq!(d, copy(0, 10));
+        assert_eq!(apply(&basis, BS, &d, basis.len()).unwrap(), basis);
+    }
+
+    #[test]
+    fn inserted_and_deleted() {
+        let basis = data(BS * 10, 1);
+        let mut new = basis[..BS * 2 + 17].to_vec();
+        new.extend(b"inserted");
+        new.extend(&basis[BS * 2 + 17..BS * 5]);
+        new.extend(&basis[BS * 6 + 100..]);
+        let d = delta(&new, BS, &sigs(&basis));
+        assert!(d.len() < BS * 3, "delta of {} bytes", d.len());
+        assert_eq!(apply(&basis, BS, &d, new.len()).unwrap(), new);
+    }
+
+    #[test]
+    fn missing_signatures() {
+        let basis = data(BS * 6, 1);
+        let mut sigs = sigs(&basis);
+        sigs[1] = None;
+        sigs[4] = None;
+        let d = delta(&basis, BS, &sigs);
+        assert_eq!(apply(&basis, BS, &d, basis.len()).unwrap(), basis);
+
+        let none = delta(&basis, BS, &[None; 6]);
+        assert!(none.len() > basis.len());
+        assert_eq!(apply(&[], BS, &none, basis.len()).unwrap(), basis);
+    }
+
+    #[test]
+    fn partial_block() {
+        let basis = data(BS * 4 + 100, 1);
+        assert_eq!(signatures(&basis, BS).len(), 4);
+        let mut new = basis.clone();
+        new.extend(b"more");
+        let d = delta(&new, BS, &sigs(&basis));
+        assert_eq!(apply(&basis, BS, &d, new.len()).unwrap(), new);
+
+        // Shorter than a block.
+        let short = data(BS - 1, 2);
+        let d = delta(&short, BS, &sigs(&basis));
+        assert_eq!(apply(&basis, BS, &d, short.len()).unwrap(), short);
+        assert_eq!(apply(&basis, BS, &delta(&[], BS, &[]), 0).unwrap(), []);
+    }
+
+    #[test]
+    fn bad_delta() {
+        let basis = data(BS * 4 + 100, 1);
+        assert_eq!(
+            apply(&basis, BS, &copy(3, 1), BS).unwrap(),
+            basis[BS * 3..BS * 4]
+        );
+        // The partial block at the end can't be copied.
+        assert!(apply(&basis, BS, &copy(4, 1), BS * 10).is_err());
+        assert!(apply(&basis, BS, &copy(2, 3), BS * 10).is_err());
+        assert!(apply(&basis, BS, &copy(u64::MAX, 2), BS * 10).is_err());
+        assert!(apply(&basis, BS, &copy(1, u64::MAX), BS * 10).is_err());
+        // Too large.
+        assert!(apply(&basis, BS, &copy(0, 4), BS * 4 - 1).is_err());
+        // Unknown op, and truncated.
+        assert!(apply(&basis, BS, &[2], BS).is_err());
+        assert!(apply(&basis, BS, &copy(0, 1)[..2], BS).is_err());
+        assert!(apply(&basis, BS, &[OP_LITERAL, 5, 1, 2], BS).is_err());
+    }
+}
@@ -0,0 +1,323 @@
+use crate::ax25codec::fcs;
+use crate::ax25ms::Frame;
+use log::{debug, info, warn};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+// Framing bytes.
+const FEND: u8 = 0xc0;
+const FESC: u8 = 0xdb;
+const TFEND: u8 = 0xdc;
+const TFESC: u8 = 0xdd;
+
+// Commands, in the low nibble of the first byte. The high nibble is the
+// port.
+const CMD_DATA: u8 = 0x00;
+const CMD_TXDELAY: u8 = 0x01;
+const CMD_PERSIST: u8 = 0x02;
+const CMD_SLOTTIME: u8 = 0x03;
+
+/// Highest port number a command byte has room for.
+const MAX_PORT: u8 = 15;
+
+/// Length of the FCS, which KISS frames don't have.
+const FCS_LEN: usize = 2;
+
+/// Longest frame to receive: the largest encoding symbol, with room for
+/// the addresses and message headers.
+const MAX_FRAME_LEN: usize = u16::MAX as usize + 1024;
+
+#[derive(clap::Args, Debug)]
+pub struct KissOpt {
+    /// KISS TNC to use over TCP, as host:port. E.g. direwolf's KISSPORT.
+    #[clap(long = "kiss-tcp", conflicts_with = "kiss_serial")]
+    pub kiss_tcp: Option<String>,
+
+    /// Serial device of a KISS TNC.
+    #[clap(long = "kiss-serial")]
+    pub kiss_serial: Option<String>,
+
+    /// Baud rate of the serial device.
+    #[clap(long = "kiss-baud", default_value = "9600")]
+    pub kiss_baud: u32,
+
+    /// TNC port to send and receive on, for TNCs with more than one.
+    #[clap(
+        long = "kiss-port",
+        default_value = "0",
+        value_parser = clap::value_parser!(u8).range(0..=MAX_PORT as i64)
+    )]
+    pub kiss_port: u8,
+
+    /// Milliseconds between keying up and sending, in steps of 10ms.
+    /// Left as the TNC has it if not given.
+    #[clap(long = "kiss-txdelay", value_parser = clap::value_parser!(u16).range(0..=2550))]
+    pub kiss_txdelay: Option<u16>,
+
+    /// Persistence, 0-255. The TNC sends with probability
+    /// (persistence+1)/256 each slot time the channel is clear.
+    #[clap(long = "kiss-persist")]
+    pub kiss_persist: Option<u8>,
+
+    /// Milliseconds of each slot time, in steps of 10ms.
+    #[clap(long = "kiss-slottime", value_parser = clap::value_parser!(u16).range(0..=2550))]
+    pub kiss_slottime: Option<u16>,
+}
+
+/// Encode one KISS frame, escaping `data`.
+pub fn encode(port: u8, cmd: u8, data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + 4);
+    out.push(FEND);
+    out.push(port << 4 | cmd);
+    for b in data {
+        match *b {
+            FEND => out.extend([FESC, TFEND]),
+            FESC => out.extend([FESC, TFESC]),
+            b => out.push(b),
+        }
+    }
+    out.push(FEND);
+    out
+}
+
+///
+/// Splits a byte stream into KISS frames.
+///
+/// Frames come out with their command byte first, unescaped. Frames
+/// longer than `MAX_FRAME_LEN` are dropped.
+///
+#[derive(Default)]
+pub struct Decoder {
+    buf: Vec<u8>,
+    escaped: bool,
+
+    /// In a frame that's too long, so skipping to the next FEND.
+    skipping: bool,
+}
+
+impl Decoder {
+    /// Add a byte, returning the frame it ends, if any.
+    pub fn push(&mut self, b: u8) -> Option<Vec<u8>> {
+        if b == FEND {
+            self.escaped = false;
+            if std::mem::take(&mut self.skipping) || self.buf.is_empty() {
+                return None;
+            }
+            return Some(std::mem::take(&mut self.buf));
+        }
+        if self.skipping {
+            return None;
+        }
+        if self.buf.len() == MAX_FRAME_LEN {
+            debug!("Dropping KISS frame longer than {} bytes", MAX_FRAME_LEN);
+            self.buf = Vec::new();
+            self.escaped = false;
+            self.skipping = true;
+            return None;
+        }
+        if self.escaped {
+            self.escaped = false;
+            self.buf.push(match b {
+                TFEND => FEND,
+                TFESC => FESC,
+                b => b,
+            });
+        } else if b == FESC {
+            self.escaped = true;
+        } else {
+            self.buf.push(b);
+        }
+        None
+    }
+}
+
+/// Open a serial device raw, at `baud`.
+fn open_serial(path: &str, baud: u32) -> std::io::Result<std::fs::File> {
+    let speed = match baud {
+        1200 => libc::B1200,
+        2400 => libc::B2400,
+        4800 => libc::B4800,
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115200 => libc::B115200,
+        230400 => libc::B230400,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("unsupported baud rate {baud}"),
+            ))
+        }
+    };
+    let file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = file.as_raw_fd();
+    // SAFETY: fd is open for as long as `file` is, and termios is plain
+    // data that tcgetattr fills in.
+    unsafe {
+        let mut t: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut t) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        libc::cfmakeraw(&mut t);
+        t.c_cflag |= libc::CLOCAL | libc::CREAD;
+        t.c_cc[libc::VMIN] = 1;
+        t.c_cc[libc::VTIME] = 0;
+        if libc::cfsetspeed(&mut t, speed) != 0 || libc::tcsetattr(fd, libc::TCSANOW, &t) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+///
+/// Connect to the TNC given by `opt`, over TCP or serial.
+///
+/// Returns a channel to send frames on, and one that frames are
+/// received on. Frames are with FCS, like ax25ms has them, so it's added
+/// to received frames and removed from sent ones.
+///
+pub async fn connect(
+    opt: &KissOpt,
+) -> std::io::Result<(mpsc::Sender<Vec<u8>>, mpsc::Receiver<Frame>)> {
+    if let Some(addr) = &opt.kiss_tcp {
+        let (reader, writer) = tokio::net::TcpStream::connect(addr).await?.into_split();
+        info!("Connected to KISS TNC {}", addr);
+        return Ok(start(reader, writer, opt));
+    }
+    let path = opt.kiss_serial.as_deref().ok_or(std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "no KISS TNC given",
+    ))?;
+    let file = open_serial(path, opt.kiss_baud)?;
+    let reader = tokio::fs::File::from_std(file.try_clone()?);
+    let writer = tokio::fs::File::from_std(file);
+    info!("Opened KISS TNC {} at {} baud", path, opt.kiss_baud);
+    Ok(start(reader, writer, opt))
+}
+
+/// Start tasks reading from and writing to the TNC.
+fn start<R, W>(
+    mut reader: R,
+    mut writer: W,
+    opt: &KissOpt,
+) -> (mpsc::Sender<Vec<u8>>, mpsc::Receiver<Frame>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+    W: AsyncWrite + Unpin + Send + 'static,
+{
+    let port = opt.kiss_port;
+
+    // Parameters go first, before any frames.
+    let mut params = Vec::new();
+    if let Some(ms) = opt.kiss_txdelay {
+        params.extend(encode(port, CMD_TXDELAY, &[(ms / 10) as u8]));
+    }
+    if let Some(p) = opt.kiss_persist {
+        params.extend(encode(port, CMD_PERSIST, &[p]));
+    }
+    if let Some(ms) = opt.kiss_slottime {
+        params.extend(encode(port, CMD_SLOTTIME, &[(ms / 10) as u8]));
+    }
+
+    let (tx, mut tx_frames) = mpsc::channel::<Vec<u8>>(32);
+    tokio::spawn(async move {
+        let mut out = params;
+        loop {
+            if !out.is_empty() {
+                if let Err(e) = async {
+                    writer.write_all(&out).await?;
+                    writer.flush().await
+                }
+                .await
+                {
+                    warn!("Failed to write to KISS TNC: {}", e);
+                    return;
+                }
+            }
+            let Some(frame) = tx_frames.recv().await else {
+                return;
+            };
+            out = encode(
+                port,
+                CMD_DATA,
+                &frame[..frame.len().saturating_sub(FCS_LEN)],
+            );
+        }
+    });
+
+    let (rx_frames, rx) = mpsc::channel(32);
+    tokio::spawn(async move {
+        let mut decoder = Decoder::default();
+        let mut buf = [0; 1024];
+        loop {
+            let n = match reader.read(&mut buf).await {
+                Ok(0) => {
+                    warn!("KISS TNC closed the connection");
+                    return;
+                }
+                Ok(n) => n,
+                Err(e) => {
+                    warn!("Failed to read from KISS TNC: {}", e);
+                    return;
+                }
+            };
+            for b in &buf[..n] {
+                let Some(mut frame) = decoder.push(*b) else {
+                    continue;
+                };
+                // Only data frames from our port.
+                if frame[0] != port << 4 | CMD_DATA {
+                    continue;
+                }
+                frame.remove(0);
+                frame.extend(fcs(&frame).to_le_bytes());
+                if rx_frames.send(Frame { payload: frame }).await.is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    (tx, rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(d: &mut Decoder, bytes: &[u8]) -> Vec<Vec<u8>> {
+        bytes.iter().filter_map(|b| d.push(*b)).collect()
+    }
+
+    #[test]
+    fn round_trip_escapes() {
+        let data = [1, FEND, 2, FESC, 3, TFEND, TFESC];
+        let encoded = encode(3, CMD_DATA, &data);
+        assert_eq!(
+            encoded,
+            [FEND, 0x30, 1, FESC, TFEND, 2, FESC, TFESC, 3, TFEND, TFESC, FEND]
+        );
+        let mut want = vec![0x30];
+        want.extend(data);
+        assert_eq!(decode_all(&mut Decoder::default(), &encoded), [want]);
+    }
+
+    #[test]
+    fn drops_oversized_frames() {
+        let mut d = Decoder::default();
+        let mut bytes = vec![FEND];
+        bytes.resize(MAX_FRAME_LEN * 2, 0x42);
+        assert!(decode_all(&mut d, &bytes).is_empty());
+        assert!(d.buf.len() <= MAX_FRAME_LEN);
+        assert_eq!(
+            decode_all(&mut d, &encode(0, CMD_DATA, b"ok")),
+            [b"\0ok".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::ops::Range;
+
+/// Smallest number of source symbols, above one, that raptor-code can
+/// reliably build a fully specified matrix for.
+const MIN_MULTI_SYMBOLS: usize = 4;
+
+/// Most source blocks a file can have, since block numbers are u16.
+pub const MAX_BLOCKS: usize = u16::MAX as usize + 1;
+
+/// Largest encoding symbol, since it must fit in a frame.
+pub const MAX_PACKET_SIZE: usize = u16::MAX as usize;
+
+/// Most source symbols in a source block that raptor codes (RFC 5053)
+/// support.
+pub const MAX_BLOCK_SYMBOLS: usize = 8192;
+
+///
+/// How a file is split into raptor source blocks.
+///
+/// Both sides need to agree on this exactly, so the uploader sends the
+/// parameters in the metadata reply, and the downloader recreates it.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockLayout {
+    /// Total file size, in bytes.
+    pub size: usize,
+
+    /// Max size of an encoding symbol.
+    pub packet_size: usize,
+
+    /// Max number of source symbols in one source block.
+    pub block_symbols: usize,
+}
+
+impl BlockLayout {
+    pub fn new(size: usize, packet_size: usize, block_symbols: usize) -> BlockLayout {
+        BlockLayout {
+            size,
+            packet_size,
+            block_symbols,
+        }
+    }
+
+    /// A layout, if it's one that can be used: parameters within limits,
+    /// and not too many source blocks.
+    pub fn checked(size: usize, packet_size: usize, block_symbols: usize) -> Option<BlockLayout> {
+        if !(1..=MAX_PACKET_SIZE).contains(&packet_size)
+            || !(1..=MAX_BLOCK_SYMBOLS).contains(&block_symbols)
+        {
+            return None;
+        }
+        let block_size = packet_size.checked_mul(block_symbols)?;
+        if size.div_ceil(block_size) > MAX_BLOCKS {
+            return None;
+        }
+        Some(BlockLayout::new(size, packet_size, block_symbols))
+    }
+
+    /// Max size of a source block, in bytes.
+    pub fn block_size(&self) -> usize {
+        self.packet_size * self.block_symbols
+    }
+
+    /// Number of source blocks.
+    pub fn blocks(&self) -> usize {
+        self.size.div_ceil(self.block_size())
+    }
+
+    /// Byte range in the file covered by source block `n`.
+    pub fn block_range(&self, n: usize) -> Range<usize> {
+        let start = n * self.block_size();
+        start..std::cmp::min(start + self.block_size(), self.size)
+    }
+
+    /// Number of source symbols in source block `n`.
+    pub fn source_symbols(&self, n: usize) -> usize {
+        let syms = self.block_range(n).len().div_ceil(self.packet_size);
+        if syms > 1 && syms < MIN_MULTI_SYMBOLS {
+            // Use smaller symbols, since 2-3 symbols won't encode.
+            return MIN_MULTI_SYMBOLS;
+        }
+        syms
+    }
+
+    /// Source block `n` of `data`, padded so that it splits evenly into
+    /// source symbols.
+    pub fn padded_block(&self, data: &[u8], n: usize) -> Vec<u8> {
+        let mut x = data[self.block_range(n)].to_vec();
+        let syms = self.source_symbols(n);
+        x.resize(x.len().div_ceil(syms) * syms, 0);
+        x
+    }
+}
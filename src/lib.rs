@@ -1,4 +1,4 @@
-use ax25::ax25_parser_client::Ax25ParserClient;
+use ax25codec::Ax25Codec;
 
 pub mod ax25ms {
     tonic::include_proto!("ax25ms");
@@ -10,17 +10,30 @@ pub mod aprs {
     tonic::include_proto!("aprs");
 }
 
+pub mod ax25codec;
+pub mod compression;
+pub mod delta;
+pub mod kiss;
+pub mod layout;
+pub mod manifest;
+pub mod merkle;
+pub mod protocol;
+pub mod sack;
+pub mod signing;
+pub mod spool;
+pub mod transport;
+
 ///
 /// make a UI packet with given payload
 ///
 pub async fn make_packet(
-    parser: &mut Ax25ParserClient<tonic::transport::Channel>,
+    parser: &mut Ax25Codec,
     dst: &str,
     src: &str,
-    payload: String,
+    payload: Vec<u8>,
 ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let req = tonic::Request::new(ax25::SerializeRequest {
-        packet: Some(ax25::Packet {
+    parser
+        .serialize(ax25::Packet {
             dst: dst.to_string(), // TODO: set callsign.
             src: src.to_string(),
             fcs: 0,
@@ -33,10 +46,8 @@ pub async fn make_packet(
             frame_type: Some(ax25::packet::FrameType::Ui(ax25::packet::Ui {
                 pid: 0xF0_i32, // TODO: some protocol ID?
                 push: 0,
-                payload: payload.into_bytes(),
+                payload,
             })),
-        }),
-        set_fcs: true,
-    });
-    Ok(parser.serialize(req).await?.into_inner().payload)
+        })
+        .await
 }
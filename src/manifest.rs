@@ -0,0 +1,199 @@
+use crate::protocol::{Hash, ProtocolError, Reader, Writer};
+
+/// First bytes of every manifest.
+const MAGIC: &[u8; 8] = b"HTMANIF1";
+
+/// MIME type of manifests in metadata replies.
+pub const MIME: &str = "application/x-hamtransfer-manifest";
+
+/// Mode bits a manifest can set. Never setuid, setgid or sticky.
+const PERMISSION_BITS: u32 = 0o777;
+
+// Entry kinds.
+const KIND_FILE: u8 = 0;
+const KIND_DIR: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    File,
+
+    /// A directory, whose hash is that of its own manifest.
+    Dir,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Name in the directory. Never a path.
+    pub name: String,
+    pub kind: Kind,
+
+    /// Unix permission bits, just the rwx ones.
+    pub mode: u32,
+
+    /// Size of the file, or of the manifest for a directory.
+    pub size: u64,
+    pub hash: Hash,
+}
+
+///
+/// A directory, served as a file of its own.
+///
+/// The encoding is the magic, followed by `kind:u8 mode:varint
+/// size:varint hash name:string` for each entry, sorted by name.
+///
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Manifest {
+    pub entries: Vec<Entry>,
+}
+
+impl Manifest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut entries = self.entries.clone();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut w = Writer(MAGIC.to_vec());
+        for e in &entries {
+            w.u8(match e.kind {
+                Kind::File => KIND_FILE,
+                Kind::Dir => KIND_DIR,
+            });
+            w.varint(e.mode as u64);
+            w.varint(e.size);
+            w.hash(&e.hash);
+            w.bytes(e.name.as_bytes());
+        }
+        w.0
+    }
+
+    /// Decode a manifest, refusing names that aren't plain file names,
+    /// and ignoring mode bits other than the permission bits.
+    pub fn decode(data: &[u8]) -> Result<Manifest, ProtocolError> {
+        let mut r = Reader(
+            data.strip_prefix(MAGIC)
+                .ok_or(ProtocolError::Invalid("not a manifest".to_string()))?,
+        );
+        let mut entries = Vec::new();
+        while !r.is_empty() {
+            let kind = match r.u8()? {
+                KIND_FILE => Kind::File,
+                KIND_DIR => Kind::Dir,
+                k => {
+                    return Err(ProtocolError::Invalid(format!(
+                        "unknown manifest entry kind {k}"
+                    )))
+                }
+            };
+            let mode = r.varint()?;
+            let size = r.varint()?;
+            let hash = r.hash()?;
+            let name = r.string()?;
+            if !valid_name(&name) {
+                return Err(ProtocolError::Invalid(format!(
+                    "bad name in manifest {name:?}"
+                )));
+            }
+            entries.push(Entry {
+                name,
+                kind,
+                mode: u32::try_from(mode)
+                    .map_err(|_| ProtocolError::Invalid(format!("bad mode {mode}")))?
+                    & PERMISSION_BITS,
+                size,
+                hash,
+            });
+        }
+        Ok(Manifest { entries })
+    }
+}
+
+/// Whether `name` is safe to use as a file name in a directory.
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "."
+        && name != ".."
+        && !name.contains(['/', '\\', '\0'])
+        && !name.chars().any(char::is_control)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str) -> Entry {
+        Entry {
+            name: name.to_string(),
+            kind: Kind::File,
+            mode: 0o644,
+            size: 10,
+            hash: Hash([1; 32]),
+        }
+    }
+
+    fn manifest(names: &[&str]) -> Vec<u8> {
+        Manifest {
+            entries: names.iter().map(|n| entry(n)).collect(),
+        }
+        .encode()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut dir = entry("sub");
+        dir.kind = Kind::Dir;
+        dir.mode = 0o755;
+        let m = Manifest {
+            entries: vec![entry("a.txt"), dir, entry("..hidden"), entry("ünï")],
+        };
+        let mut want = m.entries.clone();
+        want.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(Manifest::decode(&m.encode()).unwrap().entries, want);
+    }
+
+    #[test]
+    fn bad_names() {
+        for name in [
+            "",
+            ".",
+            "..",
+            "/",
+            "/etc/passwd",
+            "../up",
+            "a/..",
+            "a/b",
+            "a\\b",
+            "..\\up",
+            "a\0b",
+            "\0",
+            "a\nb",
+        ] {
+            assert!(!valid_name(name), "{name:?}");
+            assert!(
+                Manifest::decode(&manifest(&["ok", name])).is_err(),
+                "{name:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn special_mode_bits() {
+        let mut e = entry("a");
+        e.mode = 0o4755 | 0o2000 | 0o1000;
+        let m = Manifest { entries: vec![e] };
+        assert_eq!(
+            Manifest::decode(&m.encode()).unwrap().entries[0].mode,
+            0o755
+        );
+    }
+
+    #[test]
+    fn garbage() {
+        assert!(Manifest::decode(b"").is_err());
+        assert!(Manifest::decode(b"HTMANIF2").is_err());
+        let m = manifest(&["a"]);
+        for len in MAGIC.len() + 1..m.len() {
+            assert!(Manifest::decode(&m[..len]).is_err(), "{len}");
+        }
+        let mut bad_kind = m.clone();
+        bad_kind[MAGIC.len()] = 9;
+        assert!(Manifest::decode(&bad_kind).is_err());
+    }
+}
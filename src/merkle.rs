@@ -0,0 +1,61 @@
+use crate::protocol::Hash;
+
+/// Bytes of file per leaf of the tree.
+pub const CHUNK_SIZE: usize = 4096;
+
+// Prefixes keeping leaves and inner nodes apart, so one can't be passed
+// off as the other.
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+fn sha256(parts: &[&[u8]]) -> Hash {
+    let data = parts.concat();
+    Hash::from_hex(&sha256::digest(&data[..])).expect("sha256 is a valid hash")
+}
+
+/// Hash of one chunk of a file.
+pub fn leaf(chunk: &[u8]) -> Hash {
+    sha256(&[&[LEAF_PREFIX], chunk])
+}
+
+/// Number of chunks in a file of `size` bytes. An empty file has one,
+/// empty, chunk.
+pub fn chunks(size: usize) -> usize {
+    std::cmp::max(1, size.div_ceil(CHUNK_SIZE))
+}
+
+/// Hashes of every chunk of `data`.
+pub fn leaves(data: &[u8]) -> Vec<Hash> {
+    if data.is_empty() {
+        return vec![leaf(data)];
+    }
+    data.chunks(CHUNK_SIZE).map(leaf).collect()
+}
+
+///
+/// Root of the tree with these leaves.
+///
+/// Each level pairs up the nodes of the one below, hashing each pair
+/// with the node prefix. An odd node out moves up a level as is.
+///
+pub fn root(leaves: &[Hash]) -> Hash {
+    assert!(!leaves.is_empty(), "a tree has at least one leaf");
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => sha256(&[&[NODE_PREFIX], &left.0, &right.0]),
+                [single] => *single,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// The hash of a file, that it's known by: the root of the tree of its
+/// chunks.
+pub fn hash(data: &[u8]) -> Hash {
+    root(&leaves(data))
+}
@@ -0,0 +1,2064 @@
+use crate::compression::{Compression, COMPRESSION_DEFLATE};
+use crate::delta::Signature;
+use crate::layout::BlockLayout;
+use crate::sack::EsiSet;
+
+/// Protocol version. First byte of every binary frame.
+pub const VERSION: u8 = 1;
+
+// Message types. Second byte of every binary frame. Requests are upper
+// case, replies lower case.
+const TYPE_GET: u8 = b'G';
+const TYPE_META: u8 = b'M';
+const TYPE_META_REPLY: u8 = b'm';
+const TYPE_LIST: u8 = b'L';
+const TYPE_LIST_REPLY: u8 = b'l';
+const TYPE_SACK: u8 = b'S';
+const TYPE_DATA: u8 = b'D';
+const TYPE_CAPS: u8 = b'C';
+const TYPE_CAPS_REPLY: u8 = b'c';
+const TYPE_ERROR: u8 = b'e';
+const TYPE_SIGNED: u8 = b's';
+const TYPE_HEADER: u8 = b'h';
+const TYPE_JOIN: u8 = b'j';
+const TYPE_RANGE_REPLY: u8 = b'r';
+const TYPE_BASIS: u8 = b'B';
+const TYPE_DELTA_REPLY: u8 = b'b';
+const TYPE_TREE: u8 = b'T';
+const TYPE_TREE_REPLY: u8 = b't';
+const TYPE_FIND: u8 = b'F';
+const TYPE_FIND_REPLY: u8 = b'f';
+
+/// Length of an Ed25519 signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Smallest max packet size a station can have, to have room for data
+/// after the headers.
+pub const MIN_PACKET_SIZE: usize = 32;
+
+// GET flags.
+const GET_FLAG_META: u8 = 1;
+const GET_FLAG_RANGE: u8 = 1 << 1;
+const GET_FLAG_DELTA: u8 = 1 << 2;
+const GET_FLAG_PREFIX: u8 = 1 << 3;
+
+/// FEC codecs, as a bitmask.
+pub const FEC_RAPTOR: u64 = 1;
+
+/// Optional features, as a bitmask.
+pub const FEATURE_SACK: u64 = 1;
+pub const FEATURE_GET_META: u64 = 1 << 1;
+pub const FEATURE_DELTA: u64 = 1 << 2;
+pub const FEATURE_TREE: u64 = 1 << 3;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    Truncated,
+    UnknownVersion(u8),
+    UnknownType(u8),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "Truncated message"),
+            Self::UnknownVersion(v) => write!(f, "Unknown protocol version {v}"),
+            Self::UnknownType(t) => write!(f, "Unknown message type {t:#04x}"),
+            Self::Invalid(e) => write!(f, "Invalid message: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// How messages are encoded on the wire.
+///
+/// Decoding accepts either, so this only decides what we send.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Codec {
+    #[default]
+    Binary,
+
+    /// Human readable, for debugging with a terminal.
+    Text,
+}
+
+/// SHA-256 hash, identifying a file.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Hash(pub [u8; 32]);
+
+impl Hash {
+    pub fn from_hex(s: &str) -> Option<Hash> {
+        let bytes = from_hex(s)?;
+        Some(Hash(bytes.try_into().ok()?))
+    }
+}
+
+impl std::fmt::Debug for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Hash({self})")
+    }
+}
+
+impl std::fmt::Display for Hash {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", to_hex(&self.0))
+    }
+}
+
+///
+/// The first bytes of a hash, or all of it.
+///
+/// Requests can name a file by a prefix of its hash, to save space. The
+/// uploader answers with the whole hash, which is what the data is
+/// checked against.
+///
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct HashPrefix(Vec<u8>);
+
+impl HashPrefix {
+    /// The first `len` bytes of `hash`, but at least one.
+    pub fn new(hash: &Hash, len: usize) -> HashPrefix {
+        HashPrefix(hash.0[..len.clamp(1, hash.0.len())].to_vec())
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<HashPrefix> {
+        (1..=32)
+            .contains(&bytes.len())
+            .then(|| HashPrefix(bytes.to_vec()))
+    }
+
+    pub fn from_hex(s: &str) -> Option<HashPrefix> {
+        Self::from_bytes(&from_hex(s)?)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The hash, if this is all of it.
+    pub fn full(&self) -> Option<Hash> {
+        Some(Hash(self.0.clone().try_into().ok()?))
+    }
+
+    pub fn matches(&self, hash: &Hash) -> bool {
+        hash.0.starts_with(&self.0)
+    }
+}
+
+impl From<Hash> for HashPrefix {
+    fn from(hash: Hash) -> HashPrefix {
+        HashPrefix(hash.0.to_vec())
+    }
+}
+
+impl std::fmt::Debug for HashPrefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "HashPrefix({self})")
+    }
+}
+
+impl std::fmt::Display for HashPrefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", to_hex(&self.0))
+    }
+}
+
+/// What a station supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities {
+    pub version: u8,
+
+    /// Bitmask of `FEC_*`.
+    pub fec: u64,
+    pub max_packet_size: usize,
+
+    /// Bitmask of `FEATURE_*`.
+    pub features: u64,
+
+    /// Bitmask of `compression::COMPRESSION_*`.
+    pub compression: u64,
+}
+
+impl Capabilities {
+    /// Everything this implementation supports.
+    pub fn ours(max_packet_size: usize) -> Capabilities {
+        Capabilities {
+            version: VERSION,
+            fec: FEC_RAPTOR,
+            max_packet_size,
+            features: FEATURE_SACK | FEATURE_GET_META | FEATURE_DELTA | FEATURE_TREE,
+            compression: COMPRESSION_DEFLATE,
+        }
+    }
+
+    /// What to assume of a station that doesn't reply to capabilities
+    /// requests.
+    pub fn baseline() -> Capabilities {
+        Capabilities {
+            version: VERSION,
+            fec: FEC_RAPTOR,
+            max_packet_size: usize::MAX,
+            features: 0,
+            compression: 0,
+        }
+    }
+
+    /// What both stations support.
+    pub fn common(&self, other: &Capabilities) -> Capabilities {
+        Capabilities {
+            version: std::cmp::min(self.version, other.version),
+            fec: self.fec & other.fec,
+            max_packet_size: std::cmp::min(self.max_packet_size, other.max_packet_size),
+            features: self.features & other.features,
+            compression: self.compression & other.compression,
+        }
+    }
+
+    pub fn has(&self, feature: u64) -> bool {
+        self.features & feature == feature
+    }
+
+    /// Whether a transfer is possible with these capabilities.
+    pub fn usable(&self) -> bool {
+        self.max_packet_size >= MIN_PACKET_SIZE
+    }
+}
+
+/// Why the uploader rejected a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorReason {
+    NotFound,
+    Denied,
+    Busy,
+    TooLarge,
+
+    /// Range starting past the end of the file.
+    BadRange,
+
+    /// Hash prefix of more than one file.
+    Ambiguous,
+
+    /// Capabilities that nothing can be sent with.
+    BadCaps,
+
+    /// Reason code from a newer protocol version.
+    Unknown(u8),
+}
+
+impl ErrorReason {
+    fn code(&self) -> u8 {
+        match self {
+            Self::NotFound => 1,
+            Self::Denied => 2,
+            Self::Busy => 3,
+            Self::TooLarge => 4,
+            Self::BadRange => 5,
+            Self::Ambiguous => 6,
+            Self::BadCaps => 7,
+            Self::Unknown(c) => *c,
+        }
+    }
+
+    fn from_code(code: u8) -> ErrorReason {
+        match code {
+            1 => Self::NotFound,
+            2 => Self::Denied,
+            3 => Self::Busy,
+            4 => Self::TooLarge,
+            5 => Self::BadRange,
+            6 => Self::Ambiguous,
+            7 => Self::BadCaps,
+            c => Self::Unknown(c),
+        }
+    }
+
+    fn name(&self) -> String {
+        match self {
+            Self::NotFound => "not-found".to_string(),
+            Self::Denied => "denied".to_string(),
+            Self::Busy => "busy".to_string(),
+            Self::TooLarge => "too-large".to_string(),
+            Self::BadRange => "bad-range".to_string(),
+            Self::Ambiguous => "ambiguous".to_string(),
+            Self::BadCaps => "bad-caps".to_string(),
+            Self::Unknown(c) => c.to_string(),
+        }
+    }
+
+    fn from_name(name: &str) -> Result<ErrorReason, ProtocolError> {
+        Ok(match name {
+            "not-found" => Self::NotFound,
+            "denied" => Self::Denied,
+            "busy" => Self::Busy,
+            "too-large" => Self::TooLarge,
+            "bad-range" => Self::BadRange,
+            "ambiguous" => Self::Ambiguous,
+            "bad-caps" => Self::BadCaps,
+            _ => Self::from_code(
+                name.parse()
+                    .map_err(|_| ProtocolError::Invalid(format!("bad error reason {name:?}")))?,
+            ),
+        })
+    }
+}
+
+impl std::fmt::Display for ErrorReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::NotFound => write!(f, "not found"),
+            Self::Denied => write!(f, "denied"),
+            Self::Busy => write!(f, "busy"),
+            Self::TooLarge => write!(f, "too large"),
+            Self::BadRange => write!(f, "bad range"),
+            Self::Ambiguous => write!(f, "ambiguous hash prefix"),
+            Self::BadCaps => write!(f, "unusable capabilities"),
+            Self::Unknown(c) => write!(f, "unknown error {c}"),
+        }
+    }
+}
+
+/// Metadata about a file, other than its layout.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FileInfo {
+    /// File name on the uploader. Not to be trusted as a path.
+    pub name: String,
+
+    /// Modification time, in seconds since the Unix epoch.
+    pub mtime: u64,
+    pub mime: String,
+
+    /// Short description. May be empty.
+    pub description: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    pub hash: Hash,
+    pub name: String,
+}
+
+/// A file matching a find request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FindEntry {
+    pub hash: Hash,
+    pub size: u64,
+
+    /// Path from the top of the uploader's directory. Ends in `/` for
+    /// directories.
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Request data for a file.
+    Get {
+        tag: u16,
+        frequency: u64,
+
+        /// Bytes the downloader already has. Zero for a new request.
+        existing: u64,
+        hash: HashPrefix,
+
+        /// Also send metadata, to save a roundtrip.
+        meta: bool,
+
+        /// Only this offset and length of the file. Always answered with
+        /// a range reply before the data.
+        range: Option<(u64, u64)>,
+
+        /// Block size and number of blocks of an older copy, with
+        /// signatures sent in basis messages with the same tag. Only the
+        /// differences are sent, after a delta reply.
+        delta: Option<(u64, u64)>,
+    },
+
+    /// Signatures of blocks `first..` of the downloader's older copy of a
+    /// file.
+    Basis {
+        tag: u16,
+        hash: Hash,
+        block_size: u64,
+        first: u64,
+        sigs: Vec<Signature>,
+    },
+    Meta {
+        tag: u16,
+        hash: HashPrefix,
+    },
+    MetaReply {
+        tag: u16,
+        hash: Hash,
+
+        /// Layout of the data as sent, after compression.
+        layout: BlockLayout,
+        compression: Compression,
+
+        /// Size before compression.
+        original_size: usize,
+        info: FileInfo,
+    },
+    /// Request pages of the file listing. No pages means all of them.
+    List {
+        tag: u16,
+        pages: Vec<u16>,
+    },
+
+    /// A range of a file, sent as a file of its own.
+    RangeReply {
+        tag: u16,
+        hash: Hash,
+        offset: u64,
+
+        /// Hash of the range, which is what's sent.
+        range_hash: Hash,
+
+        /// Layout of the range as sent, after compression.
+        layout: BlockLayout,
+        compression: Compression,
+
+        /// Size before compression, which is the length of the range.
+        original_size: usize,
+    },
+
+    /// Differences between the downloader's older copy and a file, sent as
+    /// a file of its own.
+    DeltaReply {
+        tag: u16,
+        hash: Hash,
+
+        /// Size of the file the delta makes.
+        file_size: u64,
+
+        /// Hash of the delta, which is what's sent.
+        delta_hash: Hash,
+
+        /// Layout of the delta as sent, after compression.
+        layout: BlockLayout,
+        compression: Compression,
+
+        /// Size of the delta before compression.
+        original_size: usize,
+    },
+
+    /// Request the chunk hashes of a file, from chunk `first` on.
+    Tree {
+        tag: u16,
+        hash: Hash,
+        first: u64,
+    },
+
+    /// Hashes of chunks `first..` of a file. Checked against the file's
+    /// hash, which is the root of the tree of them, so not signed.
+    TreeReply {
+        tag: u16,
+        hash: Hash,
+        first: u64,
+        leaves: Vec<Hash>,
+    },
+
+    /// One page of the file listing, out of pages `0..=last`.
+    ListReply {
+        tag: u16,
+        page: u16,
+        last: u16,
+        entries: Vec<ListEntry>,
+    },
+
+    /// Look up files by name or glob pattern.
+    Find {
+        tag: u16,
+        pattern: String,
+    },
+
+    /// Files matching a find request. `total` are found, but only as
+    /// many as fit are sent.
+    FindReply {
+        tag: u16,
+        total: u64,
+        entries: Vec<FindEntry>,
+    },
+
+    /// ESIs received for blocks not yet decoded.
+    Sack {
+        tag: u16,
+        hash: Hash,
+        blocks: Vec<(u16, EsiSet)>,
+    },
+    Data {
+        tag: u16,
+        block: u16,
+        esi: u16,
+        symbol: Vec<u8>,
+    },
+
+    /// Capabilities of the requesting station.
+    Caps {
+        tag: u16,
+        caps: Capabilities,
+    },
+
+    /// Capabilities of the replying station.
+    CapsReply {
+        tag: u16,
+        caps: Capabilities,
+    },
+
+    /// Reply to a request that won't be served.
+    Error {
+        tag: u16,
+        reason: ErrorReason,
+
+        /// Files the hash prefix could be, for an ambiguous one.
+        candidates: Vec<Hash>,
+    },
+
+    /// Session parameters, sent among data frames so that they can be
+    /// decoded without the metadata reply.
+    Header {
+        tag: u16,
+        hash: Hash,
+        layout: BlockLayout,
+        compression: Compression,
+        original_size: usize,
+    },
+
+    /// Data for the request with `tag` is sent with the tag `session`
+    /// instead, shared with other stations that want the same file.
+    Join {
+        tag: u16,
+        session: u16,
+    },
+
+    /// Another message, signed by the uploader. The signature is of the
+    /// binary encoding of the message, whatever encoding is used to send
+    /// it.
+    Signed {
+        signature: [u8; SIGNATURE_LEN],
+        message: Box<Message>,
+    },
+}
+
+impl Message {
+    pub fn tag(&self) -> u16 {
+        match self {
+            Self::Get { tag, .. }
+            | Self::Meta { tag, .. }
+            | Self::MetaReply { tag, .. }
+            | Self::RangeReply { tag, .. }
+            | Self::Basis { tag, .. }
+            | Self::DeltaReply { tag, .. }
+            | Self::Tree { tag, .. }
+            | Self::TreeReply { tag, .. }
+            | Self::List { tag, .. }
+            | Self::ListReply { tag, .. }
+            | Self::Find { tag, .. }
+            | Self::FindReply { tag, .. }
+            | Self::Sack { tag, .. }
+            | Self::Data { tag, .. }
+            | Self::Caps { tag, .. }
+            | Self::CapsReply { tag, .. }
+            | Self::Error { tag, .. }
+            | Self::Header { tag, .. }
+            | Self::Join { tag, .. } => *tag,
+            Self::Signed { message, .. } => message.tag(),
+        }
+    }
+
+    pub fn encode(&self, codec: Codec) -> Vec<u8> {
+        match codec {
+            Codec::Binary => self.encode_binary(),
+            Codec::Text => self.encode_text().into_bytes(),
+        }
+    }
+
+    /// Decode a message in either encoding.
+    pub fn decode(payload: &[u8]) -> Result<Message, ProtocolError> {
+        match payload.first() {
+            None => Err(ProtocolError::Truncated),
+            Some(&VERSION) => Self::decode_binary(&payload[1..]),
+            Some(v) if !v.is_ascii_alphabetic() => Err(ProtocolError::UnknownVersion(*v)),
+            Some(_) => match std::str::from_utf8(payload) {
+                Ok(s) => Self::decode_text(s),
+                Err(e) => Err(ProtocolError::Invalid(e.to_string())),
+            },
+        }
+    }
+
+    fn signed(signature: [u8; SIGNATURE_LEN], message: Message) -> Result<Message, ProtocolError> {
+        if let Self::Signed { .. } = message {
+            return Err(ProtocolError::Invalid("nested signatures".to_string()));
+        }
+        Ok(Self::Signed {
+            signature,
+            message: Box::new(message),
+        })
+    }
+
+    fn encode_binary(&self) -> Vec<u8> {
+        let mut w = Writer(vec![VERSION]);
+        match self {
+            Self::Get {
+                tag,
+                frequency,
+                existing,
+                hash,
+                meta,
+                range,
+                delta,
+            } => {
+                let mut flags = 0;
+                if *meta {
+                    flags |= GET_FLAG_META;
+                }
+                if range.is_some() {
+                    flags |= GET_FLAG_RANGE;
+                }
+                if delta.is_some() {
+                    flags |= GET_FLAG_DELTA;
+                }
+                let full = hash.full();
+                if full.is_none() {
+                    flags |= GET_FLAG_PREFIX;
+                }
+                w.u8(TYPE_GET);
+                w.u16(*tag);
+                w.u8(flags);
+                w.varint(*frequency);
+                w.varint(*existing);
+                match full {
+                    Some(hash) => w.hash(&hash),
+                    None => w.bytes(hash.as_bytes()),
+                }
+                if let Some((offset, length)) = range {
+                    w.varint(*offset);
+                    w.varint(*length);
+                }
+                if let Some((block_size, blocks)) = delta {
+                    w.varint(*block_size);
+                    w.varint(*blocks);
+                }
+            }
+            Self::Basis {
+                tag,
+                hash,
+                block_size,
+                first,
+                sigs,
+            } => {
+                w.u8(TYPE_BASIS);
+                w.u16(*tag);
+                w.hash(hash);
+                w.varint(*block_size);
+                w.varint(*first);
+                for sig in sigs {
+                    w.u32(sig.weak);
+                    w.u32(sig.strong);
+                }
+            }
+            Self::Meta { tag, hash } => {
+                w.u8(TYPE_META);
+                w.u16(*tag);
+                // All of the rest, so a whole hash is just the hash.
+                w.0.extend(hash.as_bytes());
+            }
+            Self::MetaReply {
+                tag,
+                hash,
+                layout,
+                compression,
+                original_size,
+                info,
+            } => {
+                w.u8(TYPE_META_REPLY);
+                w.u16(*tag);
+                w.hash(hash);
+                w.varint(layout.block_symbols as u64);
+                w.varint(layout.size as u64);
+                w.varint(layout.packet_size as u64);
+                w.u8(compression.code());
+                w.varint(*original_size as u64);
+                w.bytes(info.name.as_bytes());
+                w.varint(info.mtime);
+                w.bytes(info.mime.as_bytes());
+                w.bytes(info.description.as_bytes());
+            }
+            Self::RangeReply {
+                tag,
+                hash,
+                offset,
+                range_hash,
+                layout,
+                compression,
+                original_size,
+            } => {
+                w.u8(TYPE_RANGE_REPLY);
+                w.u16(*tag);
+                w.hash(hash);
+                w.varint(*offset);
+                w.hash(range_hash);
+                w.varint(layout.block_symbols as u64);
+                w.varint(layout.size as u64);
+                w.varint(layout.packet_size as u64);
+                w.u8(compression.code());
+                w.varint(*original_size as u64);
+            }
+            Self::DeltaReply {
+                tag,
+                hash,
+                file_size,
+                delta_hash,
+                layout,
+                compression,
+                original_size,
+            } => {
+                w.u8(TYPE_DELTA_REPLY);
+                w.u16(*tag);
+                w.hash(hash);
+                w.varint(*file_size);
+                w.hash(delta_hash);
+                w.varint(layout.block_symbols as u64);
+                w.varint(layout.size as u64);
+                w.varint(layout.packet_size as u64);
+                w.u8(compression.code());
+                w.varint(*original_size as u64);
+            }
+            Self::Tree { tag, hash, first } => {
+                w.u8(TYPE_TREE);
+                w.u16(*tag);
+                w.hash(hash);
+                w.varint(*first);
+            }
+            Self::TreeReply {
+                tag,
+                hash,
+                first,
+                leaves,
+            } => {
+                w.u8(TYPE_TREE_REPLY);
+                w.u16(*tag);
+                w.hash(hash);
+                w.varint(*first);
+                for leaf in leaves {
+                    w.hash(leaf);
+                }
+            }
+            Self::List { tag, pages } => {
+                w.u8(TYPE_LIST);
+                w.u16(*tag);
+                for page in pages {
+                    w.u16(*page);
+                }
+            }
+            Self::ListReply {
+                tag,
+                page,
+                last,
+                entries,
+            } => {
+                w.u8(TYPE_LIST_REPLY);
+                w.u16(*tag);
+                w.u16(*page);
+                w.u16(*last);
+                for e in entries {
+                    w.hash(&e.hash);
+                    w.bytes(e.name.as_bytes());
+                }
+            }
+            Self::Find { tag, pattern } => {
+                w.u8(TYPE_FIND);
+                w.u16(*tag);
+                w.bytes(pattern.as_bytes());
+            }
+            Self::FindReply {
+                tag,
+                total,
+                entries,
+            } => {
+                w.u8(TYPE_FIND_REPLY);
+                w.u16(*tag);
+                w.varint(*total);
+                for e in entries {
+                    w.hash(&e.hash);
+                    w.varint(e.size);
+                    w.bytes(e.name.as_bytes());
+                }
+            }
+            Self::Sack { tag, hash, blocks } => {
+                w.u8(TYPE_SACK);
+                w.u16(*tag);
+                w.hash(hash);
+                for (block, esis) in blocks {
+                    w.u16(*block);
+                    w.bytes(esis.as_bytes());
+                }
+            }
+            Self::Data {
+                tag,
+                block,
+                esi,
+                symbol,
+            } => {
+                w.u8(TYPE_DATA);
+                w.u16(*tag);
+                w.u16(*block);
+                w.u16(*esi);
+                w.0.extend(symbol);
+            }
+            Self::Caps { tag, caps } | Self::CapsReply { tag, caps } => {
+                w.u8(match self {
+                    Self::Caps { .. } => TYPE_CAPS,
+                    _ => TYPE_CAPS_REPLY,
+                });
+                w.u16(*tag);
+                w.u8(caps.version);
+                w.varint(caps.fec);
+                w.varint(caps.max_packet_size as u64);
+                w.varint(caps.features);
+                w.varint(caps.compression);
+            }
+            Self::Error {
+                tag,
+                reason,
+                candidates,
+            } => {
+                w.u8(TYPE_ERROR);
+                w.u16(*tag);
+                w.u8(reason.code());
+                for hash in candidates {
+                    w.hash(hash);
+                }
+            }
+            Self::Header {
+                tag,
+                hash,
+                layout,
+                compression,
+                original_size,
+            } => {
+                w.u8(TYPE_HEADER);
+                w.u16(*tag);
+                w.hash(hash);
+                w.varint(layout.block_symbols as u64);
+                w.varint(layout.size as u64);
+                w.varint(layout.packet_size as u64);
+                w.u8(compression.code());
+                w.varint(*original_size as u64);
+            }
+            Self::Join { tag, session } => {
+                w.u8(TYPE_JOIN);
+                w.u16(*tag);
+                w.u16(*session);
+            }
+            Self::Signed { signature, message } => {
+                w.u8(TYPE_SIGNED);
+                w.0.extend(signature);
+                w.0.extend(message.encode_binary());
+            }
+        }
+        w.0
+    }
+
+    fn decode_binary(payload: &[u8]) -> Result<Message, ProtocolError> {
+        let mut r = Reader(payload);
+        let t = r.u8()?;
+        if t == TYPE_SIGNED {
+            let signature = r.take(SIGNATURE_LEN)?.try_into().unwrap();
+            return Self::signed(signature, Self::decode(r.rest())?);
+        }
+        let tag = r.u16()?;
+        let msg = match t {
+            TYPE_GET => {
+                let flags = r.u8()?;
+                let frequency = r.varint()?;
+                let existing = r.varint()?;
+                let hash = if flags & GET_FLAG_PREFIX != 0 {
+                    let len = r.usize()?;
+                    r.prefix(len)?
+                } else {
+                    r.hash()?.into()
+                };
+                Self::Get {
+                    tag,
+                    meta: flags & GET_FLAG_META != 0,
+                    frequency,
+                    existing,
+                    hash,
+                    range: if flags & GET_FLAG_RANGE != 0 {
+                        Some((r.varint()?, r.varint()?))
+                    } else {
+                        None
+                    },
+                    delta: if flags & GET_FLAG_DELTA != 0 {
+                        Some((r.varint()?, r.varint()?))
+                    } else {
+                        None
+                    },
+                }
+            }
+            TYPE_BASIS => {
+                let hash = r.hash()?;
+                let block_size = r.varint()?;
+                let first = r.varint()?;
+                let mut sigs = Vec::new();
+                while !r.is_empty() {
+                    sigs.push(Signature {
+                        weak: r.u32()?,
+                        strong: r.u32()?,
+                    });
+                }
+                Self::Basis {
+                    tag,
+                    hash,
+                    block_size,
+                    first,
+                    sigs,
+                }
+            }
+            TYPE_META => Self::Meta {
+                tag,
+                hash: r.prefix(r.0.len())?,
+            },
+            TYPE_META_REPLY => {
+                let hash = r.hash()?;
+                let block_symbols = r.usize()?;
+                let size = r.usize()?;
+                let packet_size = r.usize()?;
+                Self::MetaReply {
+                    tag,
+                    hash,
+                    layout: layout(size, packet_size, block_symbols)?,
+                    compression: compression(r.u8()?)?,
+                    original_size: r.usize()?,
+                    info: FileInfo {
+                        name: r.string()?,
+                        mtime: r.varint()?,
+                        mime: r.string()?,
+                        description: r.string()?,
+                    },
+                }
+            }
+            TYPE_RANGE_REPLY => {
+                let hash = r.hash()?;
+                let offset = r.varint()?;
+                let range_hash = r.hash()?;
+                let block_symbols = r.usize()?;
+                let size = r.usize()?;
+                let packet_size = r.usize()?;
+                Self::RangeReply {
+                    tag,
+                    hash,
+                    offset,
+                    range_hash,
+                    layout: layout(size, packet_size, block_symbols)?,
+                    compression: compression(r.u8()?)?,
+                    original_size: r.usize()?,
+                }
+            }
+            TYPE_DELTA_REPLY => {
+                let hash = r.hash()?;
+                let file_size = r.varint()?;
+                let delta_hash = r.hash()?;
+                let block_symbols = r.usize()?;
+                let size = r.usize()?;
+                let packet_size = r.usize()?;
+                Self::DeltaReply {
+                    tag,
+                    hash,
+                    file_size,
+                    delta_hash,
+                    layout: layout(size, packet_size, block_symbols)?,
+                    compression: compression(r.u8()?)?,
+                    original_size: r.usize()?,
+                }
+            }
+            TYPE_TREE => Self::Tree {
+                tag,
+                hash: r.hash()?,
+                first: r.varint()?,
+            },
+            TYPE_TREE_REPLY => {
+                let hash = r.hash()?;
+                let first = r.varint()?;
+                let mut leaves = Vec::new();
+                while !r.is_empty() {
+                    leaves.push(r.hash()?);
+                }
+                Self::TreeReply {
+                    tag,
+                    hash,
+                    first,
+                    leaves,
+                }
+            }
+            TYPE_LIST => {
+                let mut pages = Vec::new();
+                while !r.is_empty() {
+                    pages.push(r.u16()?);
+                }
+                Self::List { tag, pages }
+            }
+            TYPE_LIST_REPLY => {
+                let page = r.u16()?;
+                let last = r.u16()?;
+                let mut entries = Vec::new();
+                while !r.is_empty() {
+                    entries.push(ListEntry {
+                        hash: r.hash()?,
+                        name: r.string()?,
+                    });
+                }
+                Self::ListReply {
+                    tag,
+                    page,
+                    last,
+                    entries,
+                }
+            }
+            TYPE_FIND => Self::Find {
+                tag,
+                pattern: r.string()?,
+            },
+            TYPE_FIND_REPLY => {
+                let total = r.varint()?;
+                let mut entries = Vec::new();
+                while !r.is_empty() {
+                    entries.push(FindEntry {
+                        hash: r.hash()?,
+                        size: r.varint()?,
+                        name: r.string()?,
+                    });
+                }
+                Self::FindReply {
+                    tag,
+                    total,
+                    entries,
+                }
+            }
+            TYPE_SACK => {
+                let hash = r.hash()?;
+                let mut blocks = Vec::new();
+                while !r.is_empty() {
+                    blocks.push((r.u16()?, EsiSet::from_bytes(r.bytes()?)));
+                }
+                Self::Sack { tag, hash, blocks }
+            }
+            TYPE_DATA => Self::Data {
+                tag,
+                block: r.u16()?,
+                esi: r.u16()?,
+                symbol: r.rest().to_vec(),
+            },
+            TYPE_CAPS => Self::Caps {
+                tag,
+                caps: r.caps()?,
+            },
+            TYPE_CAPS_REPLY => Self::CapsReply {
+                tag,
+                caps: r.caps()?,
+            },
+            TYPE_ERROR => {
+                let reason = ErrorReason::from_code(r.u8()?);
+                let mut candidates = Vec::new();
+                while !r.is_empty() {
+                    candidates.push(r.hash()?);
+                }
+                Self::Error {
+                    tag,
+                    reason,
+                    candidates,
+                }
+            }
+            TYPE_HEADER => {
+                let hash = r.hash()?;
+                let block_symbols = r.usize()?;
+                let size = r.usize()?;
+                let packet_size = r.usize()?;
+                Self::Header {
+                    tag,
+                    hash,
+                    layout: layout(size, packet_size, block_symbols)?,
+                    compression: compression(r.u8()?)?,
+                    original_size: r.usize()?,
+                }
+            }
+            TYPE_JOIN => Self::Join {
+                tag,
+                session: r.u16()?,
+            },
+            t => return Err(ProtocolError::UnknownType(t)),
+        };
+        if !r.is_empty() {
+            return Err(ProtocolError::Invalid("trailing data".to_string()));
+        }
+        Ok(msg)
+    }
+
+    fn encode_text(&self) -> String {
+        match self {
+            Self::Get {
+                tag,
+                frequency,
+                existing,
+                hash,
+                meta,
+                range,
+                delta,
+            } => {
+                let mut cmd = "G".to_string();
+                if *meta {
+                    cmd.push('M');
+                }
+                if range.is_some() {
+                    cmd.push('R');
+                }
+                if delta.is_some() {
+                    cmd.push('D');
+                }
+                let mut s = format!("{cmd} {tag} {frequency} {existing} {hash}");
+                if let Some((offset, length)) = range {
+                    s.push_str(&format!(" {offset} {length}"));
+                }
+                if let Some((block_size, blocks)) = delta {
+                    s.push_str(&format!(" {block_size} {blocks}"));
+                }
+                s
+            }
+            Self::Basis {
+                tag,
+                hash,
+                block_size,
+                first,
+                sigs,
+            } => {
+                let mut s = format!("B {tag} {hash} {block_size} {first}");
+                for sig in sigs {
+                    s.push_str(&format!(" {:08x}{:08x}", sig.weak, sig.strong));
+                }
+                s
+            }
+            Self::Meta { tag, hash } => format!("M {tag} {hash}"),
+            Self::MetaReply {
+                tag,
+                hash,
+                layout,
+                compression,
+                original_size,
+                info,
+            } => format!(
+                "m {tag} {hash} {} {} {} {} {original_size} {} {}\n{}\n{}",
+                layout.block_symbols,
+                layout.size,
+                layout.packet_size,
+                compression.code(),
+                info.mtime,
+                info.mime,
+                info.name,
+                info.description
+            ),
+            Self::RangeReply {
+                tag,
+                hash,
+                offset,
+                range_hash,
+                layout,
+                compression,
+                original_size,
+            } => format!(
+                "r {tag} {hash} {offset} {range_hash} {} {} {} {} {original_size}",
+                layout.block_symbols,
+                layout.size,
+                layout.packet_size,
+                compression.code()
+            ),
+            Self::DeltaReply {
+                tag,
+                hash,
+                file_size,
+                delta_hash,
+                layout,
+                compression,
+                original_size,
+            } => format!(
+                "b {tag} {hash} {file_size} {delta_hash} {} {} {} {} {original_size}",
+                layout.block_symbols,
+                layout.size,
+                layout.packet_size,
+                compression.code()
+            ),
+            Self::Tree { tag, hash, first } => format!("T {tag} {hash} {first}"),
+            Self::TreeReply {
+                tag,
+                hash,
+                first,
+                leaves,
+            } => {
+                let mut s = format!("t {tag} {hash} {first}");
+                for leaf in leaves {
+                    s.push_str(&format!(" {leaf}"));
+                }
+                s
+            }
+            Self::List { tag, pages } => {
+                let mut s = format!("L {tag}");
+                for page in pages {
+                    s.push_str(&format!(" {page}"));
+                }
+                s
+            }
+            Self::ListReply {
+                tag,
+                page,
+                last,
+                entries,
+            } => {
+                let mut s = format!("l {tag} {page} {last}");
+                for e in entries {
+                    s.push_str(&format!("\n{} {}", e.hash, e.name));
+                }
+                s
+            }
+            Self::Find { tag, pattern } => format!("F {tag}\n{pattern}"),
+            Self::FindReply {
+                tag,
+                total,
+                entries,
+            } => {
+                let mut s = format!("f {tag} {total}");
+                for e in entries {
+                    s.push_str(&format!("\n{} {} {}", e.hash, e.size, e.name));
+                }
+                s
+            }
+            Self::Sack { tag, hash, blocks } => {
+                let mut s = format!("S {tag} {hash}");
+                for (block, esis) in blocks {
+                    s.push_str(&format!(" {block}:{}", esis.to_hex()));
+                }
+                s
+            }
+            Self::Data {
+                tag,
+                block,
+                esi,
+                symbol,
+            } => format!("D {tag} {block} {esi} {}", to_hex(symbol)),
+            Self::Caps { tag, caps } | Self::CapsReply { tag, caps } => {
+                let cmd = match self {
+                    Self::Caps { .. } => "C",
+                    _ => "c",
+                };
+                format!(
+                    "{cmd} {tag} {} {} {} {} {}",
+                    caps.version, caps.fec, caps.max_packet_size, caps.features, caps.compression
+                )
+            }
+            Self::Error {
+                tag,
+                reason,
+                candidates,
+            } => {
+                let mut s = format!("e {tag} {}", reason.name());
+                for hash in candidates {
+                    s.push_str(&format!(" {hash}"));
+                }
+                s
+            }
+            Self::Header {
+                tag,
+                hash,
+                layout,
+                compression,
+                original_size,
+            } => format!(
+                "h {tag} {hash} {} {} {} {} {original_size}",
+                layout.block_symbols,
+                layout.size,
+                layout.packet_size,
+                compression.code()
+            ),
+            Self::Join { tag, session } => format!("j {tag} {session}"),
+            Self::Signed { signature, message } => {
+                format!("s {}\n{}", to_hex(signature), message.encode_text())
+            }
+        }
+    }
+
+    fn decode_text(s: &str) -> Result<Message, ProtocolError> {
+        let (first, rest) = s.split_once('\n').unwrap_or((s, ""));
+        let mut t = Tokens(first.split(' '));
+        let cmd = t.next()?;
+        if cmd == "s" {
+            let signature = from_hex(t.next()?)
+                .and_then(|s| s.try_into().ok())
+                .ok_or(ProtocolError::Invalid("bad signature".to_string()))?;
+            return Self::signed(signature, Self::decode(rest.as_bytes())?);
+        }
+        let tag = t.parse()?;
+        let msg = match cmd {
+            "G" | "GM" | "GR" | "GMR" | "GD" | "GMD" | "GRD" | "GMRD" => Self::Get {
+                tag,
+                meta: cmd.contains('M'),
+                frequency: t.parse()?,
+                existing: t.parse()?,
+                hash: t.prefix()?,
+                range: if cmd.contains('R') {
+                    Some((t.parse()?, t.parse()?))
+                } else {
+                    None
+                },
+                delta: if cmd.contains('D') {
+                    Some((t.parse()?, t.parse()?))
+                } else {
+                    None
+                },
+            },
+            "B" => {
+                let hash = t.hash()?;
+                let block_size = t.parse()?;
+                let first = t.parse()?;
+                let mut sigs = Vec::new();
+                for sig in t.0.by_ref() {
+                    let bad = || ProtocolError::Invalid(format!("bad signature {sig:?}"));
+                    if sig.len() != 16 {
+                        return Err(bad());
+                    }
+                    sigs.push(Signature {
+                        weak: u32::from_str_radix(&sig[..8], 16).map_err(|_| bad())?,
+                        strong: u32::from_str_radix(&sig[8..], 16).map_err(|_| bad())?,
+                    });
+                }
+                Self::Basis {
+                    tag,
+                    hash,
+                    block_size,
+                    first,
+                    sigs,
+                }
+            }
+            "M" => Self::Meta {
+                tag,
+                hash: t.prefix()?,
+            },
+            "m" => {
+                let hash = t.hash()?;
+                let block_symbols = t.parse()?;
+                let size = t.parse()?;
+                let packet_size = t.parse()?;
+                let compression = compression(t.parse()?)?;
+                let original_size = t.parse()?;
+                let mtime = t.parse()?;
+                let mime = t.next()?.to_string();
+                let (name, description) = rest.split_once('\n').unwrap_or((rest, ""));
+                Self::MetaReply {
+                    tag,
+                    hash,
+                    layout: layout(size, packet_size, block_symbols)?,
+                    compression,
+                    original_size,
+                    info: FileInfo {
+                        name: name.to_string(),
+                        mtime,
+                        mime,
+                        description: description.to_string(),
+                    },
+                }
+            }
+            "r" => {
+                let hash = t.hash()?;
+                let offset = t.parse()?;
+                let range_hash = t.hash()?;
+                let block_symbols = t.parse()?;
+                let size = t.parse()?;
+                let packet_size = t.parse()?;
+                Self::RangeReply {
+                    tag,
+                    hash,
+                    offset,
+                    range_hash,
+                    layout: layout(size, packet_size, block_symbols)?,
+                    compression: compression(t.parse()?)?,
+                    original_size: t.parse()?,
+                }
+            }
+            "b" => {
+                let hash = t.hash()?;
+                let file_size = t.parse()?;
+                let delta_hash = t.hash()?;
+                let block_symbols = t.parse()?;
+                let size = t.parse()?;
+                let packet_size = t.parse()?;
+                Self::DeltaReply {
+                    tag,
+                    hash,
+                    file_size,
+                    delta_hash,
+                    layout: layout(size, packet_size, block_symbols)?,
+                    compression: compression(t.parse()?)?,
+                    original_size: t.parse()?,
+                }
+            }
+            "T" => Self::Tree {
+                tag,
+                hash: t.hash()?,
+                first: t.parse()?,
+            },
+            "t" => {
+                let hash = t.hash()?;
+                let first = t.parse()?;
+                let mut leaves = Vec::new();
+                for leaf in t.0.by_ref() {
+                    leaves.push(Tokens(std::iter::once(leaf)).hash()?);
+                }
+                Self::TreeReply {
+                    tag,
+                    hash,
+                    first,
+                    leaves,
+                }
+            }
+            "L" => {
+                let mut pages = Vec::new();
+                for page in t.0.by_ref() {
+                    pages.push(Tokens(std::iter::once(page)).parse()?);
+                }
+                Self::List { tag, pages }
+            }
+            "l" => {
+                let page = t.parse()?;
+                let last = t.parse()?;
+                let mut entries = Vec::new();
+                for line in rest.lines() {
+                    let (hash, name) = line
+                        .split_once(' ')
+                        .ok_or(ProtocolError::Invalid(format!("bad list entry {line:?}")))?;
+                    entries.push(ListEntry {
+                        hash: Tokens(std::iter::once(hash)).hash()?,
+                        name: name.to_string(),
+                    });
+                }
+                Self::ListReply {
+                    tag,
+                    page,
+                    last,
+                    entries,
+                }
+            }
+            "F" => Self::Find {
+                tag,
+                pattern: rest.to_string(),
+            },
+            "f" => {
+                let total = t.parse()?;
+                let mut entries = Vec::new();
+                for line in rest.lines() {
+                    let bad = || ProtocolError::Invalid(format!("bad find entry {line:?}"));
+                    let (hash, line) = line.split_once(' ').ok_or_else(bad)?;
+                    let (size, name) = line.split_once(' ').ok_or_else(bad)?;
+                    entries.push(FindEntry {
+                        hash: Tokens(std::iter::once(hash)).hash()?,
+                        size: Tokens(std::iter::once(size)).parse()?,
+                        name: name.to_string(),
+                    });
+                }
+                Self::FindReply {
+                    tag,
+                    total,
+                    entries,
+                }
+            }
+            "S" => {
+                let hash = t.hash()?;
+                let mut blocks = Vec::new();
+                for entry in t.0.by_ref() {
+                    let (block, bits) = entry
+                        .split_once(':')
+                        .ok_or(ProtocolError::Invalid(format!("bad SACK entry {entry:?}")))?;
+                    let esis = EsiSet::from_hex(bits)
+                        .ok_or(ProtocolError::Invalid(format!("bad ESI bitmap {bits:?}")))?;
+                    blocks.push((Tokens(std::iter::once(block)).parse()?, esis));
+                }
+                Self::Sack { tag, hash, blocks }
+            }
+            "D" => Self::Data {
+                tag,
+                block: t.parse()?,
+                esi: t.parse()?,
+                symbol: from_hex(t.next()?)
+                    .ok_or(ProtocolError::Invalid("bad symbol hex".to_string()))?,
+            },
+            "C" => Self::Caps {
+                tag,
+                caps: t.caps()?,
+            },
+            "c" => Self::CapsReply {
+                tag,
+                caps: t.caps()?,
+            },
+            "h" => {
+                let hash = t.hash()?;
+                let block_symbols = t.parse()?;
+                let size = t.parse()?;
+                let packet_size = t.parse()?;
+                Self::Header {
+                    tag,
+                    hash,
+                    layout: layout(size, packet_size, block_symbols)?,
+                    compression: compression(t.parse()?)?,
+                    original_size: t.parse()?,
+                }
+            }
+            "e" => {
+                let reason = ErrorReason::from_name(t.next()?)?;
+                let mut candidates = Vec::new();
+                for hash in t.0.by_ref() {
+                    candidates.push(Tokens(std::iter::once(hash)).hash()?);
+                }
+                Self::Error {
+                    tag,
+                    reason,
+                    candidates,
+                }
+            }
+            "j" => Self::Join {
+                tag,
+                session: t.parse()?,
+            },
+            _ => return Err(ProtocolError::Invalid(format!("unknown command {cmd:?}"))),
+        };
+        if t.0.next().is_some() {
+            return Err(ProtocolError::Invalid("trailing data".to_string()));
+        }
+        Ok(msg)
+    }
+}
+
+/// Block layout from the wire, rejecting what can't be used.
+fn layout(
+    size: usize,
+    packet_size: usize,
+    block_symbols: usize,
+) -> Result<BlockLayout, ProtocolError> {
+    BlockLayout::checked(size, packet_size, block_symbols).ok_or(ProtocolError::Invalid(format!(
+        "bad block layout of {size} bytes in symbols of {packet_size} bytes, {block_symbols} per block"
+    )))
+}
+
+fn compression(code: u8) -> Result<Compression, ProtocolError> {
+    Compression::from_code(code).ok_or(ProtocolError::Invalid(format!(
+        "unknown compression {code}"
+    )))
+}
+
+pub(crate) struct Writer(pub(crate) Vec<u8>);
+
+impl Writer {
+    pub(crate) fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.0.extend(v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.0.extend(v.to_be_bytes());
+    }
+
+    /// Unsigned LEB128.
+    pub(crate) fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8 & 0x7f) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8);
+    }
+
+    pub(crate) fn hash(&mut self, h: &Hash) {
+        self.0.extend(h.0);
+    }
+
+    /// Length prefixed.
+    pub(crate) fn bytes(&mut self, b: &[u8]) {
+        self.varint(b.len() as u64);
+        self.0.extend(b);
+    }
+}
+
+pub(crate) struct Reader<'a>(pub(crate) &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ProtocolError> {
+        if self.0.len() < n {
+            return Err(ProtocolError::Truncated);
+        }
+        let (ret, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(ret)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.0)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, ProtocolError> {
+        let mut ret = 0_u64;
+        for shift in (0..64).step_by(7) {
+            let b = self.u8()?;
+            let bits = (b & 0x7f) as u64;
+            if (bits << shift) >> shift != bits {
+                return Err(ProtocolError::Invalid("varint too large".to_string()));
+            }
+            ret |= bits << shift;
+            if b & 0x80 == 0 {
+                return Ok(ret);
+            }
+        }
+        Err(ProtocolError::Invalid("varint too long".to_string()))
+    }
+
+    pub(crate) fn usize(&mut self) -> Result<usize, ProtocolError> {
+        let v = self.varint()?;
+        usize::try_from(v).map_err(|e| ProtocolError::Invalid(e.to_string()))
+    }
+
+    pub(crate) fn hash(&mut self) -> Result<Hash, ProtocolError> {
+        Ok(Hash(self.take(32)?.try_into().unwrap()))
+    }
+
+    fn prefix(&mut self, len: usize) -> Result<HashPrefix, ProtocolError> {
+        HashPrefix::from_bytes(self.take(len)?).ok_or(ProtocolError::Invalid(format!(
+            "bad hash prefix length {len}"
+        )))
+    }
+
+    fn caps(&mut self) -> Result<Capabilities, ProtocolError> {
+        Ok(Capabilities {
+            version: self.u8()?,
+            fec: self.varint()?,
+            max_packet_size: self.usize()?,
+            features: self.varint()?,
+            compression: self.varint()?,
+        })
+    }
+
+    pub(crate) fn bytes(&mut self) -> Result<&'a [u8], ProtocolError> {
+        let len = self.usize()?;
+        self.take(len)
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, ProtocolError> {
+        String::from_utf8(self.bytes()?.to_vec()).map_err(|e| ProtocolError::Invalid(e.to_string()))
+    }
+}
+
+/// Space separated fields of a text message.
+struct Tokens<'a, I: Iterator<Item = &'a str>>(I);
+
+impl<'a, I: Iterator<Item = &'a str>> Tokens<'a, I> {
+    fn next(&mut self) -> Result<&'a str, ProtocolError> {
+        self.0.next().ok_or(ProtocolError::Truncated)
+    }
+
+    fn parse<T: std::str::FromStr>(&mut self) -> Result<T, ProtocolError> {
+        let s = self.next()?;
+        s.parse()
+            .map_err(|_| ProtocolError::Invalid(format!("bad number {s:?}")))
+    }
+
+    fn caps(&mut self) -> Result<Capabilities, ProtocolError> {
+        Ok(Capabilities {
+            version: self.parse()?,
+            fec: self.parse()?,
+            max_packet_size: self.parse()?,
+            features: self.parse()?,
+            compression: self.parse()?,
+        })
+    }
+
+    fn hash(&mut self) -> Result<Hash, ProtocolError> {
+        let s = self.next()?;
+        Hash::from_hex(s).ok_or(ProtocolError::Invalid(format!("bad hash {s:?}")))
+    }
+
+    fn prefix(&mut self) -> Result<HashPrefix, ProtocolError> {
+        let s = self.next()?;
+        HashPrefix::from_hex(s).ok_or(ProtocolError::Invalid(format!("bad hash prefix {s:?}")))
+    }
+}
+
+pub fn to_hex(b: &[u8]) -> String {
+    b.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(b: u8) -> Hash {
+        Hash([b; 32])
+    }
+
+    fn layout() -> BlockLayout {
+        BlockLayout::new(100_000, 200, 256)
+    }
+
+    fn esis(list: &[u16]) -> EsiSet {
+        let mut set = EsiSet::new();
+        for esi in list {
+            set.insert(*esi);
+        }
+        set
+    }
+
+    /// One of every message, with fields that aren't all defaults.
+    fn messages() -> Vec<Message> {
+        let caps = Capabilities {
+            max_packet_size: 1000,
+            ..Capabilities::ours(200)
+        };
+        let mut msgs = vec![
+            Message::Get {
+                tag: 1,
+                frequency: 0,
+                existing: 0,
+                hash: hash(1).into(),
+                meta: false,
+                range: None,
+                delta: None,
+            },
+            Message::Get {
+                tag: 0xffff,
+                frequency: 145_800_000,
+                existing: 1 << 40,
+                hash: HashPrefix::new(&hash(2), 8),
+                meta: true,
+                range: Some((1000, 2000)),
+                delta: Some((512, 300)),
+            },
+            Message::Basis {
+                tag: 2,
+                hash: hash(3),
+                block_size: 512,
+                first: 10,
+                sigs: vec![
+                    Signature {
+                        weak: 1,
+                        strong: u32::MAX,
+                    },
+                    Signature {
+                        weak: 0xdead_beef,
+                        strong: 7,
+                    },
+                ],
+            },
+            Message::Meta {
+                tag: 3,
+                hash: HashPrefix::new(&hash(4), 4),
+            },
+            Message::Meta {
+                tag: 3,
+                hash: hash(4).into(),
+            },
+            Message::MetaReply {
+                tag: 4,
+                hash: hash(5),
+                layout: layout(),
+                compression: Compression::Deflate,
+                original_size: 123_456,
+                info: FileInfo {
+                    name: "some file.txt".to_string(),
+                    mtime: 1_700_000_000,
+                    mime: "text/plain".to_string(),
+                    description: "A file, with spaces".to_string(),
+                },
+            },
+            Message::MetaReply {
+                tag: 4,
+                hash: hash(5),
+                layout: BlockLayout::new(0, 1, 1),
+                compression: Compression::None,
+                original_size: 0,
+                info: FileInfo::default(),
+            },
+            Message::List {
+                tag: 5,
+                pages: vec![],
+            },
+            Message::List {
+                tag: 5,
+                pages: vec![0, 3, 65535],
+            },
+            Message::RangeReply {
+                tag: 6,
+                hash: hash(6),
+                offset: 4096,
+                range_hash: hash(7),
+                layout: layout(),
+                compression: Compression::None,
+                original_size: 100_000,
+            },
+            Message::DeltaReply {
+                tag: 7,
+                hash: hash(8),
+                file_size: 1 << 33,
+                delta_hash: hash(9),
+                layout: layout(),
+                compression: Compression::Deflate,
+                original_size: 200_000,
+            },
+            Message::Tree {
+                tag: 8,
+                hash: hash(10),
+                first: 5,
+            },
+            Message::TreeReply {
+                tag: 9,
+                hash: hash(11),
+                first: 5,
+                leaves: vec![hash(12), hash(13)],
+            },
+            Message::ListReply {
+                tag: 10,
+                page: 1,
+                last: 2,
+                entries: vec![
+                    ListEntry {
+                        hash: hash(14),
+                        name: "a.txt".to_string(),
+                    },
+                    ListEntry {
+                        hash: hash(15),
+                        name: "sub dir/".to_string(),
+                    },
+                ],
+            },
+            Message::Find {
+                tag: 11,
+                pattern: "logs/*.txt".to_string(),
+            },
+            Message::FindReply {
+                tag: 12,
+                total: 3,
+                entries: vec![FindEntry {
+                    hash: hash(16),
+                    size: 42,
+                    name: "logs/a b.txt".to_string(),
+                }],
+            },
+            Message::Sack {
+                tag: 13,
+                hash: hash(17),
+                blocks: vec![(0, esis(&[])), (2, esis(&[0, 1, 9, 300]))],
+            },
+            Message::Data {
+                tag: 14,
+                block: 3,
+                esi: 1000,
+                symbol: (0..=255).collect(),
+            },
+            Message::Caps { tag: 15, caps },
+            Message::CapsReply { tag: 16, caps },
+            Message::Error {
+                tag: 17,
+                reason: ErrorReason::NotFound,
+                candidates: vec![],
+            },
+            Message::Error {
+                tag: 17,
+                reason: ErrorReason::Ambiguous,
+                candidates: vec![hash(18), hash(19)],
+            },
+            Message::Error {
+                tag: 17,
+                reason: ErrorReason::Unknown(200),
+                candidates: vec![],
+            },
+            Message::Header {
+                tag: 18,
+                hash: hash(20),
+                layout: layout(),
+                compression: Compression::Deflate,
+                original_size: 100,
+            },
+            Message::Join {
+                tag: 19,
+                session: 20,
+            },
+        ];
+        let signed: Vec<Message> = msgs
+            .iter()
+            .filter(|m| matches!(m, Message::MetaReply { .. } | Message::Error { .. }))
+            .map(|m| Message::Signed {
+                signature: [0x5a; SIGNATURE_LEN],
+                message: Box::new(m.clone()),
+            })
+            .collect();
+        msgs.extend(signed);
+        msgs
+    }
+
+    #[test]
+    fn round_trip_binary() {
+        for msg in messages() {
+            let encoded = msg.encode(Codec::Binary);
+            assert_eq!(encoded[0], VERSION);
+            assert_eq!(Message::decode(&encoded), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn round_trip_text() {
+        for msg in messages() {
+            let encoded = msg.encode(Codec::Text);
+            assert!(encoded[0].is_ascii_alphabetic(), "{msg:?}");
+            assert_eq!(Message::decode(&encoded), Ok(msg));
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        for msg in messages() {
+            let encoded = msg.encode(Codec::Binary);
+            for len in 0..encoded.len() {
+                // Some messages end in a variable length field, so a
+                // shorter one can still be valid, just never the same.
+                if let Ok(m) = Message::decode(&encoded[..len]) {
+                    assert_ne!(m, msg);
+                }
+            }
+        }
+        assert_eq!(Message::decode(&[]), Err(ProtocolError::Truncated));
+        assert_eq!(Message::decode(&[VERSION]), Err(ProtocolError::Truncated));
+    }
+
+    #[test]
+    fn garbage() {
+        assert_eq!(
+            Message::decode(&[VERSION + 1, 0]),
+            Err(ProtocolError::UnknownVersion(VERSION + 1))
+        );
+        assert!(Message::decode(b"Q 1 2").is_err());
+        assert!(Message::decode(b"G notanumber").is_err());
+        assert!(Message::decode(&[b'G', 0xff, 0xfe]).is_err());
+
+        // Every byte string decodes to something or an error, never a
+        // panic.
+        let mut x = 1_u32;
+        for len in 0..200 {
+            let data: Vec<u8> = (0..len)
+                .map(|_| {
+                    x ^= x << 13;
+                    x ^= x >> 17;
+                    x ^= x << 5;
+                    x as u8
+                })
+                .collect();
+            let _ = Message::decode(&data);
+            let mut with_version = vec![VERSION];
+            with_version.extend(&data);
+            let _ = Message::decode(&with_version);
+        }
+    }
+
+    #[test]
+    fn bad_layout() {
+        let reply = |layout: BlockLayout| {
+            Message::Header {
+                tag: 1,
+                hash: hash(1),
+                layout,
+                compression: Compression::None,
+                original_size: 0,
+            }
+            .encode(Codec::Binary)
+        };
+        for layout in [
+            BlockLayout::new(1, 0, 1),
+            BlockLayout::new(1, 1, 0),
+            BlockLayout::new(1, 1 << 16, 1),
+            BlockLayout::new(1, 1, 8193),
+            BlockLayout::new(usize::MAX, 1, 1),
+        ] {
+            assert!(Message::decode(&reply(layout)).is_err(), "{layout:?}");
+        }
+        assert!(Message::decode(&reply(BlockLayout::new(65536, 1, 1))).is_ok());
+        assert!(Message::decode(&reply(BlockLayout::new(65537, 1, 1))).is_err());
+    }
+
+    #[test]
+    fn varint() {
+        for v in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX - 1, u64::MAX] {
+            let mut w = Writer(Vec::new());
+            w.varint(v);
+            let mut r = Reader(&w.0);
+            assert_eq!(r.varint(), Ok(v));
+            assert!(r.is_empty());
+        }
+        // Truncated.
+        assert_eq!(Reader(&[0x80]).varint(), Err(ProtocolError::Truncated));
+        assert_eq!(Reader(&[]).varint(), Err(ProtocolError::Truncated));
+        // More than 64 bits.
+        let mut too_large = vec![0xff; 9];
+        too_large.push(0x02);
+        assert!(matches!(
+            Reader(&too_large).varint(),
+            Err(ProtocolError::Invalid(_))
+        ));
+        // More than ten bytes.
+        let mut too_long = vec![0x80; 10];
+        too_long.push(0);
+        assert!(matches!(
+            Reader(&too_long).varint(),
+            Err(ProtocolError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn reader() {
+        let mut r = Reader(&[0, 1, 2, 3, 4]);
+        assert_eq!(r.u16(), Ok(1));
+        assert_eq!(r.u32(), Err(ProtocolError::Truncated));
+        assert_eq!(r.u8(), Ok(2));
+        assert_eq!(r.rest(), [3, 4]);
+        assert!(r.is_empty());
+        assert_eq!(r.u8(), Err(ProtocolError::Truncated));
+
+        // Length past the end.
+        assert_eq!(Reader(&[5, b'a']).bytes(), Err(ProtocolError::Truncated));
+        assert_eq!(
+            Reader(&[0xff, 0xff, 0xff, 0xff, 0x0f]).bytes(),
+            Err(ProtocolError::Truncated)
+        );
+        assert!(Reader(&[2, 0xc3, 0x28]).string().is_err());
+        assert_eq!(Reader(&[2, b'o', b'k']).string(), Ok("ok".to_string()));
+        assert_eq!(Reader(&[0; 31]).hash(), Err(ProtocolError::Truncated));
+    }
+}
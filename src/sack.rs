@@ -0,0 +1,72 @@
+use crate::protocol::{from_hex, to_hex};
+
+///
+/// Set of received encoding symbol IDs (ESIs) for one source block.
+///
+/// On the wire it's a bitmap, where bit `n % 8` (LSB first) of byte
+/// `n / 8` is set if ESI `n` was received. Trailing zero bytes are
+/// dropped, so a set of low ESIs stays small.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EsiSet {
+    bits: Vec<u8>,
+}
+
+impl EsiSet {
+    pub fn new() -> EsiSet {
+        EsiSet::default()
+    }
+
+    pub fn insert(&mut self, esi: u16) {
+        let (byte, bit) = (esi as usize / 8, esi % 8);
+        if byte >= self.bits.len() {
+            self.bits.resize(byte + 1, 0);
+        }
+        self.bits[byte] |= 1 << bit;
+    }
+
+    pub fn contains(&self, esi: u16) -> bool {
+        let (byte, bit) = (esi as usize / 8, esi % 8);
+        match self.bits.get(byte) {
+            Some(b) => b & (1 << bit) != 0,
+            None => false,
+        }
+    }
+
+    /// Number of ESIs in the set.
+    pub fn len(&self) -> usize {
+        self.bits.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The lowest `n` ESIs not in the set.
+    pub fn missing(&self, n: usize) -> Vec<u16> {
+        (0..=u16::MAX)
+            .filter(|esi| !self.contains(*esi))
+            .take(n)
+            .collect()
+    }
+
+    /// Bitmap with trailing zero bytes removed.
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.bits.iter().rposition(|b| *b != 0).map_or(0, |p| p + 1);
+        &self.bits[..len]
+    }
+
+    pub fn from_bytes(bits: &[u8]) -> EsiSet {
+        EsiSet {
+            bits: bits.to_vec(),
+        }
+    }
+
+    pub fn to_hex(&self) -> String {
+        to_hex(self.as_bytes())
+    }
+
+    pub fn from_hex(s: &str) -> Option<EsiSet> {
+        Some(EsiSet { bits: from_hex(s)? })
+    }
+}
@@ -0,0 +1,82 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use rand::Rng;
+use std::io::{Error, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::protocol::{from_hex, to_hex, Codec, Message};
+
+///
+/// Load the uploader's signing key from `path`, creating a new one if the
+/// file doesn't exist.
+///
+/// The file holds the hex encoded 32 byte secret key.
+///
+pub fn load_or_create_key(path: &str) -> std::io::Result<SigningKey> {
+    match std::fs::read_to_string(path) {
+        Ok(s) => {
+            let bytes = from_hex(s.trim())
+                .and_then(|b| <[u8; 32]>::try_from(b).ok())
+                .ok_or(Error::new(ErrorKind::InvalidData, "bad signing key"))?;
+            Ok(SigningKey::from_bytes(&bytes))
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let key = SigningKey::from_bytes(&rand::rng().random());
+            // Only readable by us, since it's secret.
+            std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(path)?
+                .write_all(format!("{}\n", to_hex(key.as_bytes())).as_bytes())?;
+            Ok(key)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+///
+/// Load public keys to trust.
+///
+/// One hex encoded key per line, optionally followed by a comment, like
+/// the station it belongs to. Empty lines and lines starting with `#` are
+/// ignored.
+///
+pub fn load_trusted_keys(path: &str) -> std::io::Result<Vec<VerifyingKey>> {
+    let mut keys = Vec::new();
+    for line in std::fs::read_to_string(path)?.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let hex = line.split_whitespace().next().unwrap_or("");
+        let key = from_hex(hex)
+            .and_then(|b| <[u8; 32]>::try_from(b).ok())
+            .and_then(|b| VerifyingKey::from_bytes(&b).ok())
+            .ok_or(Error::new(
+                ErrorKind::InvalidData,
+                format!("bad public key {hex:?}"),
+            ))?;
+        keys.push(key);
+    }
+    Ok(keys)
+}
+
+pub fn public_key_hex(key: &SigningKey) -> String {
+    to_hex(key.verifying_key().as_bytes())
+}
+
+/// Wrap `msg` in a signature.
+pub fn sign(msg: Message, key: &SigningKey) -> Message {
+    Message::Signed {
+        signature: key.sign(&msg.encode(Codec::Binary)).to_bytes(),
+        message: Box::new(msg),
+    }
+}
+
+/// Whether `signature` of `msg` is by one of `keys`.
+pub fn verify(msg: &Message, signature: &[u8; 64], keys: &[VerifyingKey]) -> bool {
+    let data = msg.encode(Codec::Binary);
+    let signature = Signature::from_bytes(signature);
+    keys.iter()
+        .any(|k| k.verify_strict(&data, &signature).is_ok())
+}
@@ -0,0 +1,191 @@
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::compression::Compression;
+use crate::layout::BlockLayout;
+use crate::protocol::Hash;
+
+/// First bytes of every spool file.
+const MAGIC: &[u8; 8] = b"HTSPOOL1";
+
+/// Magic, hash, size, packet size, block symbols, and compression.
+const HEADER_LEN: usize = MAGIC.len() + 32 + 3 * 8 + 1;
+
+///
+/// Encoding symbols received for a file, saved as they arrive so that an
+/// interrupted download can be resumed.
+///
+/// The file starts with a header saying what file and encoding the
+/// symbols are for, followed by one record per symbol: `block:u16
+/// esi:u16 len:u16` (big endian) and the symbol itself.
+///
+pub struct Spool {
+    file: File,
+}
+
+impl Spool {
+    ///
+    /// Open the spool file at `path`, returning the symbols already in it.
+    ///
+    /// A spool for some other file or encoding is started over. A record
+    /// cut short, because the downloader was killed while writing it, is
+    /// dropped.
+    ///
+    #[allow(clippy::type_complexity)]
+    pub fn open(
+        path: &str,
+        hash: &Hash,
+        layout: &BlockLayout,
+        compression: Compression,
+    ) -> std::io::Result<(Spool, Vec<(u16, u16, Vec<u8>)>)> {
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+
+        let header = header(hash, layout, compression);
+        if !data.starts_with(&header) {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&header)?;
+            return Ok((Spool { file }, Vec::new()));
+        }
+
+        let mut symbols = Vec::new();
+        let mut pos = HEADER_LEN;
+        while let Some(rec) = data.get(pos..pos + 6) {
+            let block = u16::from_be_bytes([rec[0], rec[1]]);
+            let esi = u16::from_be_bytes([rec[2], rec[3]]);
+            let len = u16::from_be_bytes([rec[4], rec[5]]) as usize;
+            let symbol = match data.get(pos + 6..pos + 6 + len) {
+                Some(symbol) => symbol,
+                None => break,
+            };
+            symbols.push((block, esi, symbol.to_vec()));
+            pos += 6 + len;
+        }
+        file.set_len(pos as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok((Spool { file }, symbols))
+    }
+
+    /// Save an encoding symbol.
+    pub fn add(&mut self, block: u16, esi: u16, symbol: &[u8]) -> std::io::Result<()> {
+        let len = u16::try_from(symbol.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "symbol too large")
+        })?;
+        let mut rec = Vec::with_capacity(6 + symbol.len());
+        rec.extend(block.to_be_bytes());
+        rec.extend(esi.to_be_bytes());
+        rec.extend(len.to_be_bytes());
+        rec.extend(symbol);
+        self.file.write_all(&rec)
+    }
+}
+
+fn header(hash: &Hash, layout: &BlockLayout, compression: Compression) -> Vec<u8> {
+    let mut h = Vec::with_capacity(HEADER_LEN);
+    h.extend(MAGIC);
+    h.extend(hash.0);
+    h.extend((layout.size as u64).to_be_bytes());
+    h.extend((layout.packet_size as u64).to_be_bytes());
+    h.extend((layout.block_symbols as u64).to_be_bytes());
+    h.push(compression.code());
+    h
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layout() -> BlockLayout {
+        BlockLayout::new(10_000, 200, 16)
+    }
+
+    /// A spool file path of its own for each test.
+    fn path(name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("hamtransfer-spool-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().into_owned()
+    }
+
+    /// A spool with two symbols in it.
+    fn write(path: &str) {
+        let (mut spool, symbols) = Spool::open(path, &Hash([1; 32]), &layout(), Compression::None)
+            .expect("open new spool");
+        assert!(symbols.is_empty());
+        spool.add(0, 1, b"first").expect("add");
+        spool.add(3, 2, b"second").expect("add");
+    }
+
+    #[test]
+    fn reload() {
+        let path = path("reload");
+        write(&path);
+        let (mut spool, symbols) =
+            Spool::open(&path, &Hash([1; 32]), &layout(), Compression::None).unwrap();
+        assert_eq!(
+            symbols,
+            [(0, 1, b"first".to_vec()), (3, 2, b"second".to_vec())]
+        );
+        spool.add(1, 1, b"third").unwrap();
+        let (_, symbols) =
+            Spool::open(&path, &Hash([1; 32]), &layout(), Compression::None).unwrap();
+        assert_eq!(symbols.len(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_record() {
+        let path = path("truncated");
+        write(&path);
+        let full = std::fs::metadata(&path).unwrap().len();
+        // Cut short in the symbol, and in the record header.
+        for cut in [3, 6 + 6 - 2] {
+            let f = File::options().write(true).open(&path).unwrap();
+            f.set_len(full - cut).unwrap();
+            let (mut spool, symbols) =
+                Spool::open(&path, &Hash([1; 32]), &layout(), Compression::None).unwrap();
+            assert_eq!(symbols, [(0, 1, b"first".to_vec())]);
+            // The partial record is gone, so new ones follow the last whole one.
+            spool.add(3, 2, b"second").unwrap();
+            drop(spool);
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), full);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn header_mismatch() {
+        let path = path("mismatch");
+        let other_layout = BlockLayout::new(10_000, 100, 16);
+        for (hash, layout, compression) in [
+            (Hash([2; 32]), layout(), Compression::None),
+            (Hash([1; 32]), other_layout, Compression::None),
+            (Hash([1; 32]), layout(), Compression::Deflate),
+        ] {
+            write(&path);
+            let (_, symbols) = Spool::open(&path, &hash, &layout, compression).unwrap();
+            assert!(symbols.is_empty());
+            // Started over, for the new file.
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), HEADER_LEN as u64);
+            std::fs::remove_file(&path).unwrap();
+        }
+        // Garbage, and a file shorter than the header.
+        for junk in [
+            &b"not a spool file at all, but long enough to be a header........"[..],
+            b"HT",
+        ] {
+            std::fs::write(&path, junk).unwrap();
+            let (_, symbols) =
+                Spool::open(&path, &Hash([1; 32]), &layout(), Compression::None).unwrap();
+            assert!(symbols.is_empty());
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,78 @@
+use crate::ax25ms::router_service_client::RouterServiceClient;
+use crate::ax25ms::{Frame, SendRequest, StreamRequest};
+use crate::kiss::{self, KissOpt};
+use log::warn;
+use tokio::sync::mpsc;
+use tokio_stream::StreamExt;
+
+///
+/// Where frames are sent: an ax25ms router, or a KISS TNC directly.
+///
+/// Frames are whole AX.25 frames, with FCS.
+///
+#[derive(Clone)]
+pub enum Transport {
+    Router(RouterServiceClient<tonic::transport::Channel>),
+    Kiss(mpsc::Sender<Vec<u8>>),
+}
+
+impl Transport {
+    ///
+    /// Connect to ax25ms routers, receiving from `rx` and sending to `tx`.
+    ///
+    /// Returns the transport, and the channel received frames come in on.
+    ///
+    pub async fn router(
+        rx: String,
+        tx: String,
+    ) -> Result<(Transport, mpsc::Receiver<Frame>), Box<dyn std::error::Error>> {
+        let txclient = RouterServiceClient::connect(tx).await?;
+        let mut stream = RouterServiceClient::connect(rx)
+            .await?
+            .stream_frames(StreamRequest {})
+            .await?
+            .into_inner();
+        let (frames, rx) = mpsc::channel(32);
+        tokio::spawn(async move {
+            while let Some(item) = stream.next().await {
+                let frame = match item {
+                    Ok(frame) => frame,
+                    Err(e) => {
+                        warn!("Failed to receive frame: {}", e);
+                        return;
+                    }
+                };
+                if frames.send(frame).await.is_err() {
+                    return;
+                }
+            }
+        });
+        Ok((Transport::Router(txclient), rx))
+    }
+
+    /// Connect to a KISS TNC, like `router()`.
+    pub async fn kiss(
+        opt: &KissOpt,
+    ) -> Result<(Transport, mpsc::Receiver<Frame>), Box<dyn std::error::Error>> {
+        let (tx, rx) = kiss::connect(opt).await?;
+        Ok((Transport::Kiss(tx), rx))
+    }
+
+    /// Send a frame.
+    pub async fn send(&mut self, frame: Vec<u8>) -> Result<(), Box<dyn std::error::Error>> {
+        match self {
+            Transport::Router(client) => {
+                client
+                    .send(tonic::Request::new(SendRequest {
+                        frame: Some(Frame { payload: frame }),
+                    }))
+                    .await?;
+            }
+            Transport::Kiss(tx) => tx
+                .send(frame)
+                .await
+                .map_err(|_| "KISS TNC connection is closed")?,
+        }
+        Ok(())
+    }
+}